@@ -9,18 +9,23 @@ KERNEL=$1
 
 TRACES="pci_nvme*"
 
+# The interrupt controller QEMU gives us. This has to match the cargo
+# feature the kernel was built with:
+#   aplic-imsic (aia-msi, the default), aplic (aia-direct), none (plic)
+AIA=${AIA:-aplic-imsic}
+
 PARAMS+=" -nographic"
-PARAMS+=" -machine virt,aclint=on,aia=aplic-imsic"
+PARAMS+=" -machine virt,aclint=on,aia=${AIA}"
 PARAMS+=" -cpu rv32"
 PARAMS+=" -d guest_errors,unimp"
-PARAMS+=" -smp 1"
+PARAMS+=" -smp 4"
 PARAMS+=" -m 32M"
 # PARAMS+=" -gdb unix:debug.pipe,server,nowait"
 PARAMS+=" -serial mon:stdio"
 PARAMS+=" -device pcie-root-port,id=bridge1,multifunction=off,chassis=0,slot=1,bus=pcie.0,addr=01.0"
 PARAMS+=" -device pcie-root-port,id=bridge2,multifunction=off,chassis=1,slot=2,bus=pcie.0,addr=02.0"
-# PARAMS+=" -device pcie-root-port,id=bridge3,multifunction=off,chassis=2,slot=3,bus=pcie.0,addr=03.0"
-# PARAMS+=" -device pcie-root-port,id=bridge4,multifunction=off,chassis=3,slot=4,bus=pcie.0,addr=04.0"
+PARAMS+=" -device pcie-root-port,id=bridge3,multifunction=off,chassis=2,slot=3,bus=pcie.0,addr=03.0"
+PARAMS+=" -device pcie-root-port,id=bridge4,multifunction=off,chassis=3,slot=4,bus=pcie.0,addr=04.0"
 PARAMS+=" -device qemu-xhci,bus=bridge1,id=xhci"
 PARAMS+=" -device usb-tablet,id=usbtablet"
 # PARAMS+=" -device virtio-rng-pci-non-transitional,bus=bridge1,id=rng"
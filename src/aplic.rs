@@ -2,17 +2,56 @@
 //! Stephen Marz
 //! 1 Jun 2022
 
+use crate::{
+    imsic::{
+        imsic_alloc, imsic_copy_handler, imsic_disable, imsic_disable_on, imsic_enable,
+        imsic_enable_on, imsic_free, imsic_register, imsic_reserve, imsic_send,
+        imsic_take_pending, PrivMode, IMSIC_HART_STRIDE,
+    },
+    smp::hart_online,
+    timer::{timer_deadline, timer_expired},
+    volatile::Volatile,
+    MAX_HARTS,
+};
+use core::ptr::{addr_of, read_volatile};
+
 // These MMIO values are hard coded in the QEMU virt
 // machine.
 // M-mode APLIC
 const APLIC_M: usize = 0xc00_0000;
 // S-mode APLIC
 const APLIC_S: usize = 0xd00_0000;
-// S-mode interrupt delivery controller
-const APLIC_S_IDC: usize = 0xd00_4000;
+// A domain's interrupt delivery controls start this far into its page
+const APLIC_IDC_OFFSET: usize = 0x4000;
+
+// The number of wired sources an APLIC domain can have (1..=1023).
+const APLIC_NUM_SOURCES: usize = 1024;
+
+// domaincfg.DM: 1 = deliver MSIs, 0 = deliver directly through the IDCs
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+// genmsi.Busy: set from when genmsi is written until the MSI has been sent
+const GENMSI_BUSY: u32 = 1 << 12;
+// How long to wait for genmsi's busy bit or the test message to come in
+const GENMSI_TIMEOUT_MS: u64 = 100;
+
+// msiaddrcfgh fields that say where each hart's interrupt file is. The
+// low LHXW bits of the hart number are shifted left by LHXS + 12 and
+// added to the base address. LHXW is only in mmsiaddrcfgh and is used
+// for both levels.
+const MSIADDRCFGH_LHXW_SHIFT: u32 = 12;
+const MSIADDRCFGH_LHXW_MASK: u32 = 7 << MSIADDRCFGH_LHXW_SHIFT;
+const MSIADDRCFGH_LHXS_SHIFT: u32 = 20;
+
+// A source nothing on virt is wired to, used by `aplic_affinity_test`
+const AFFINITY_TEST_IRQ: u32 = 63;
+
+// sourcecfg.D: the source is delegated to the child in bits 9:0
+const SOURCECFG_D: u32 = 1 << 10;
 
 #[repr(u32)]
 #[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum SourceModes {
     Inactive = 0,
     Detached = 1,
@@ -22,85 +61,131 @@ enum SourceModes {
     LevelLow = 7,
 }
 
+impl SourceModes {
+    /// # Overview
+    /// Decode sourcecfg.SM. 2 and 3 are reserved.
+    fn from_bits(bits: u32) -> Option<Self> {
+        match bits & 7 {
+            0 => Some(Self::Inactive),
+            1 => Some(Self::Detached),
+            4 => Some(Self::RisingEdge),
+            5 => Some(Self::FallingEdge),
+            6 => Some(Self::LevelHigh),
+            7 => Some(Self::LevelLow),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Inactive => "inactive",
+            Self::Detached => "detached",
+            Self::RisingEdge => "rising edge",
+            Self::FallingEdge => "falling edge",
+            Self::LevelHigh => "level high",
+            Self::LevelLow => "level low",
+        }
+    }
+}
+
+/// A decoded sourcecfg register
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SourceCfg {
+    Delegated(u32),
+    Mode(SourceModes),
+    // SM is one of the reserved values
+    Reserved(u32),
+}
+
+/// A decoded target register. Which one it is depends on domaincfg.DM.
+#[derive(Clone, Copy)]
+enum Target {
+    Msi { hart: u32, guest: u32, eiid: u32 },
+    Direct { hart: u32, prio: u32 },
+}
+
 #[repr(C)]
 struct Aplic {
-    pub domaincfg: u32,
-    pub sourcecfg: [u32; 1023],
+    pub domaincfg: Volatile<u32>,
+    pub sourcecfg: [Volatile<u32>; 1023],
     _reserved1: [u8; 0xBC0],
 
-    pub mmsiaddrcfg: u32,
-    pub mmsiaddrcfgh: u32,
-    pub smsiaddrcfg: u32,
-    pub smsiaddrcfgh: u32,
+    pub mmsiaddrcfg: Volatile<u32>,
+    pub mmsiaddrcfgh: Volatile<u32>,
+    pub smsiaddrcfg: Volatile<u32>,
+    pub smsiaddrcfgh: Volatile<u32>,
     _reserved2: [u8; 0x30],
 
-    pub setip: [u32; 32],
+    pub setip: [Volatile<u32>; 32],
     _reserved3: [u8; 92],
 
-    pub setipnum: u32,
+    pub setipnum: Volatile<u32>,
     _reserved4: [u8; 0x20],
 
-    pub in_clrip: [u32; 32],
+    pub in_clrip: [Volatile<u32>; 32],
     _reserved5: [u8; 92],
 
-    pub clripnum: u32,
+    pub clripnum: Volatile<u32>,
     _reserved6: [u8; 32],
 
-    pub setie: [u32; 32],
+    pub setie: [Volatile<u32>; 32],
     _reserved7: [u8; 92],
 
-    pub setienum: u32,
+    pub setienum: Volatile<u32>,
     _reserved8: [u8; 32],
 
-    pub clrie: [u32; 32],
+    pub clrie: [Volatile<u32>; 32],
     _reserved9: [u8; 92],
 
-    pub clrienum: u32,
+    pub clrienum: Volatile<u32>,
     _reserved10: [u8; 32],
 
-    pub setipnum_le: u32,
-    pub setipnum_be: u32,
+    pub setipnum_le: Volatile<u32>,
+    pub setipnum_be: Volatile<u32>,
     _reserved11: [u8; 4088],
 
-    pub genmsi: u32,
-    pub target: [u32; 1023],
+    pub genmsi: Volatile<u32>,
+    pub target: [Volatile<u32>; 1023],
 }
 
 type AplicMode = crate::imsic::PrivMode;
 
 #[allow(dead_code)]
 impl Aplic {
-    const fn ptr(mode: AplicMode) -> *mut Self {
-        let k = match mode {
-            AplicMode::Machine => APLIC_M,
-            AplicMode::Supervisor => APLIC_S,
-        };
-        k as *mut Self
+    fn ptr(mode: AplicMode) -> *mut Self {
+        aplic_domain(domain_for(mode)).base as *mut Self
     }
 
+    /// # Overview
+    /// Get the registers of the domain that handles a privilege mode: the
+    /// root domain for M mode, and the domain that interrupts the harts'
+    /// S mode for S mode.
     pub fn as_ref<'a>(mode: AplicMode) -> &'a Self {
         unsafe { Self::ptr(mode).as_ref().unwrap() }
     }
 
-    pub fn as_mut<'a>(mode: AplicMode) -> &'a mut Self {
-        unsafe { Self::ptr(mode).as_mut().unwrap() }
-    }
-
     /// # Overview
-    /// Set the MSI target physical address. This only accepts the lower
-    /// 32-bits of an address.
+    /// Set the MSI target physical address, and where each hart's
+    /// interrupt file is from there. This only accepts the lower 32-bits
+    /// of an address.
     /// ## Arguments
     /// * `mode` the MSI mode (machine or supervisor)
-    /// * `addr` the physical address for messages. This MUST be page aligned.
-    pub fn set_msiaddr(&mut self, mode: AplicMode, addr: usize) {
+    /// * `addr` the physical address of hart 0's interrupt file. This MUST
+    ///   be page aligned.
+    pub fn set_msiaddr(&self, mode: AplicMode, addr: usize) {
+        // Enough hart index bits for every hart, and the interrupt files
+        // are IMSIC_HART_STRIDE apart.
+        let lhxw = MAX_HARTS.next_power_of_two().trailing_zeros() << MSIADDRCFGH_LHXW_SHIFT;
+        let lhxs = (IMSIC_HART_STRIDE.trailing_zeros() - 12) << MSIADDRCFGH_LHXS_SHIFT;
         match mode {
             AplicMode::Machine => {
-                self.mmsiaddrcfg = (addr >> 12) as u32;
-                self.mmsiaddrcfgh = 0;
+                self.mmsiaddrcfg.write((addr >> 12) as u32);
+                self.mmsiaddrcfgh.write(lhxw | lhxs);
             }
             AplicMode::Supervisor => {
-                self.smsiaddrcfg = (addr >> 12) as u32;
-                self.smsiaddrcfgh = 0;
+                self.smsiaddrcfg.write((addr >> 12) as u32);
+                self.smsiaddrcfgh.write(lhxs);
+                self.mmsiaddrcfgh.modify(|cfgh| cfgh & !MSIADDRCFGH_LHXW_MASK | lhxw);
             }
         }
     }
@@ -112,9 +197,12 @@ impl Aplic {
     /// * `hart` - the hart that will receive interrupts from this irq
     /// * `guest` - the guest identifier to send these interrupts
     /// * `eiid` - the identification number of the irq (usually the same as the irq itself)
-    pub fn set_target_msi(&mut self, irq: u32, hart: u32, guest: u32, eiid: u32) {
+    /// ## Returns
+    /// `false` if the target did not take the value
+    pub fn set_target_msi(&self, irq: u32, hart: u32, guest: u32, eiid: u32) -> bool {
         assert!(irq > 0 && irq < 1024);
-        self.target[irq as usize - 1] = (hart << 18) | (guest << 12) | eiid;
+        let target = &self.target[irq as usize - 1];
+        write_check(target, (hart << 18) | (guest << 12) | eiid, "target", irq)
     }
 
     /// # Overview
@@ -123,11 +211,36 @@ impl Aplic {
     /// * `irq` - the interrupt to set
     /// * `hart` - the hart that will receive interrupts from this irq
     /// * `prio` - the priority of this direct interrupt.
-    pub fn set_target_direct(&mut self, irq: u32, hart: u32, prio: u32) {
+    /// ## Returns
+    /// `false` if the target did not take the value
+    pub fn set_target_direct(&self, irq: u32, hart: u32, prio: u32) -> bool {
         assert!(irq > 0 && irq < 1024);
-        self.target[irq as usize - 1] = (hart << 18) | (prio & 0xFF);
+        let target = &self.target[irq as usize - 1];
+        write_check(target, (hart << 18) | (prio & 0xFF), "target", irq)
     }
 
+    /// # Overview
+    /// Read back a source's target. An inactive or delegated source has
+    /// a target of 0.
+    /// ## Arguments
+    /// * `irq` the interrupt number
+    pub fn target(&self, irq: u32) -> Target {
+        assert!(irq > 0 && irq < 1024);
+        let target = self.target[irq as usize - 1].read();
+        let hart = target >> 18;
+        if self.msimode() {
+            Target::Msi {
+                hart,
+                guest: target >> 12 & 0x3F,
+                eiid: target & 0x7FF,
+            }
+        } else {
+            Target::Direct {
+                hart,
+                prio: target & 0xFF,
+            }
+        }
+    }
 
     /// # Overview
     /// Setup a source configuration to a particular mode.
@@ -135,9 +248,12 @@ impl Aplic {
     /// ## Arguments
     /// * `irq` the interrupt number to set
     /// * `mode` the source mode--how the interrupt is triggered.
-    pub fn set_sourcecfg(&mut self, irq: u32, mode: SourceModes) {
+    /// ## Returns
+    /// `false` if the domain rejected the mode, such as when the parent
+    /// has not delegated the source to this domain
+    pub fn set_sourcecfg(&self, irq: u32, mode: SourceModes) -> bool {
         assert!(irq > 0 && irq < 1024);
-        self.sourcecfg[irq as usize - 1] = mode as u32;
+        write_check(&self.sourcecfg[irq as usize - 1], mode as u32, "sourcecfg", irq)
     }
 
     /// # Overview
@@ -145,9 +261,29 @@ impl Aplic {
     /// ## Arguments
     /// * `irq` the interrupt number to delegate
     /// * `child` the child to delegate this interrupt to
-    pub fn sourcecfg_delegate(&mut self, irq: u32, child: u32) {
+    /// ## Returns
+    /// `false` if the domain has no such child
+    pub fn sourcecfg_delegate(&self, irq: u32, child: u32) -> bool {
         assert!(irq > 0 && irq < 1024);
-        self.sourcecfg[irq as usize - 1] = 1 << 10 | child & 0x3ff;
+        let sourcecfg = &self.sourcecfg[irq as usize - 1];
+        write_check(sourcecfg, SOURCECFG_D | child & 0x3ff, "sourcecfg", irq)
+    }
+
+    /// # Overview
+    /// Read back and decode a source configuration.
+    /// ## Arguments
+    /// * `irq` the interrupt number
+    pub fn sourcecfg(&self, irq: u32) -> SourceCfg {
+        assert!(irq > 0 && irq < 1024);
+        let cfg = self.sourcecfg[irq as usize - 1].read();
+        if cfg & SOURCECFG_D != 0 {
+            SourceCfg::Delegated(cfg & 0x3FF)
+        } else {
+            match SourceModes::from_bits(cfg) {
+                Some(mode) => SourceCfg::Mode(mode),
+                None => SourceCfg::Reserved(cfg & 7),
+            }
+        }
     }
 
     /// # Overview
@@ -156,13 +292,22 @@ impl Aplic {
     /// * `bigendian` `true`: the APLIC uses big endian byte order, `false`: the APLIC uses little endian byte order.
     /// * `msimode` `true`: the APLIC will send MSIs for interrupts, `false`: the APLIC will only trigger actual wires.
     /// * `enabled` `true`: this APLIC is enabled and can receive/send interrupts, `false`: the APLIC domain is disabled.
-    pub fn set_domaincfg(&mut self, bigendian: bool, msimode: bool, enabled: bool) {
+    pub fn set_domaincfg(&self, bigendian: bool, msimode: bool, enabled: bool) {
         // Rust library assures that converting a bool into u32 will use
         // 1 for true and 0 for false
         let enabled = u32::from(enabled);
         let msimode = u32::from(msimode);
         let bigendian = u32::from(bigendian);
-        self.domaincfg = (enabled << 8) | (msimode << 2) | bigendian;
+        self.domaincfg.write((enabled << 8) | (msimode << 2) | bigendian);
+    }
+
+    /// # Overview
+    /// See whether this domain delivers MSIs. DM is WARL, so a domain
+    /// that can only do one of the two modes ignores what we write.
+    /// # Returns
+    /// `true` if domaincfg.DM is set (MSI mode), `false` for direct mode
+    pub fn msimode(&self) -> bool {
+        self.domaincfg.read() & DOMAINCFG_DM != 0
     }
 
     /// # Overview
@@ -170,17 +315,68 @@ impl Aplic {
     /// ## Arguments
     /// * `irq` the interrupt number
     /// * `enabled` true: enable interrupt, false: disable interrupt
-    pub fn set_ie(&mut self, irq: u32, enabled: bool) {
+    /// ## Returns
+    /// `false` if the bit did not change, which happens when the source
+    /// is inactive or delegated
+    pub fn set_ie(&self, irq: u32, enabled: bool) -> bool {
         assert!(irq > 0 && irq < 1024);
         let irqidx = irq as usize / 32;
         let irqbit = irq as usize % 32;
         if enabled {
             // self.setienum = irq;
-            self.setie[irqidx] = 1 << irqbit;
+            self.setie[irqidx].write(1 << irqbit);
         } else {
             // self.clrienum = irq;
-            self.clrie[irqidx] = 1 << irqbit;
+            self.clrie[irqidx].write(1 << irqbit);
+        }
+        if self.ie(irq) != enabled {
+            println!("APLIC IRQ {} did not take IE = {}.", irq, u32::from(enabled));
+            return false;
+        }
+        true
+    }
+
+    /// # Overview
+    /// See whether a source is enabled. Reading setie gives the enable
+    /// bits.
+    pub fn ie(&self, irq: u32) -> bool {
+        assert!(irq > 0 && irq < 1024);
+        self.setie[irq as usize / 32].read() >> (irq % 32) & 1 == 1
+    }
+
+    /// # Overview
+    /// See whether a source is pending. Reading setip gives the pending
+    /// bits.
+    pub fn ip(&self, irq: u32) -> bool {
+        assert!(irq > 0 && irq < 1024);
+        self.setip[irq as usize / 32].read() >> (irq % 32) & 1 == 1
+    }
+
+    /// # Overview
+    /// Send an MSI from this domain, as if a source targeting `hart` and
+    /// `eiid` had fired. Since the APLIC sends MSIs in order, once this
+    /// one is out, every MSI the domain sent before it is too.
+    /// ## Arguments
+    /// * `hart` - the hart whose interrupt file gets the MSI. M-mode
+    ///   domains send to M-mode files and S-mode domains to S-mode files.
+    /// * `eiid` - the identity to send
+    /// ## Returns
+    /// `false` if genmsi stayed busy
+    pub fn genmsi(&self, hart: u32, eiid: u32) -> bool {
+        // Writes while busy are ignored, so wait out anyone else's.
+        if !self.genmsi_wait() {
+            return false;
         }
+        self.genmsi.write(hart << 18 | eiid & 0x7FF);
+        self.genmsi_wait()
+    }
+
+    /// # Overview
+    /// Poll genmsi until it is no longer busy.
+    /// ## Returns
+    /// `false` if it was still busy after GENMSI_TIMEOUT_MS
+    fn genmsi_wait(&self) -> bool {
+        wait_until(|| self.genmsi.read() & GENMSI_BUSY == 0)
     }
 
     /// # Overview
@@ -188,28 +384,286 @@ impl Aplic {
     /// ## Arguments
     /// * `irq` the interrupt number
     /// * `pending` true: set the bit to 1, false: clear the bit to 0
-    pub fn set_ip(&mut self, irq: u32, pending: bool) {
+    pub fn set_ip(&self, irq: u32, pending: bool) {
         assert!(irq > 0 && irq < 1024);
         let irqidx = irq as usize / 32;
         let irqbit = irq as usize % 32;
         if pending {
             // self.setipnum = irq;
-            self.setip[irqidx] = 1 << irqbit;
+            self.setip[irqidx].write(1 << irqbit);
         } else {
             // self.clripnum = irq;
-            self.in_clrip[irqidx] = 1 << irqbit;
+            self.in_clrip[irqidx].write(1 << irqbit);
+        }
+    }
+}
+
+/// # Overview
+/// Poll until something happens or GENMSI_TIMEOUT_MS goes by.
+/// # Returns
+/// `false` if `done` never returned `true`
+fn wait_until(done: impl Fn() -> bool) -> bool {
+    let deadline = timer_deadline(GENMSI_TIMEOUT_MS);
+    loop {
+        // Look at the clock first, so `done` gets one last look after the
+        // deadline passes.
+        let expired = timer_expired(deadline);
+        if done() {
+            return true;
+        }
+        if expired {
+            return false;
+        }
+    }
+}
+
+/// One interrupt domain. Domains form a tree rooted at the M-level
+/// domain that the wired sources come into. A domain can delegate any
+/// source it has to one of its children, which are numbered in the order
+/// they appear in APLIC_DOMAINS.
+pub struct AplicDomain {
+    pub name: &'static str,
+    // The MMIO address of the domain's registers
+    pub base: usize,
+    // The privilege level the domain interrupts
+    pub mode: AplicMode,
+    // The index of the parent in APLIC_DOMAINS, `None` for the root
+    pub parent: Option<usize>,
+}
+
+// The domain tree of QEMU's virt machine: the M-level root with a single
+// S-level child. Other platforms (or a test of deeper trees) describe
+// theirs here. The root must come first and every parent must come
+// before its children.
+static APLIC_DOMAINS: [AplicDomain; 2] = [
+    AplicDomain {
+        name: "M",
+        base: APLIC_M,
+        mode: AplicMode::Machine,
+        parent: None,
+    },
+    AplicDomain {
+        name: "S",
+        base: APLIC_S,
+        mode: AplicMode::Supervisor,
+        parent: Some(0),
+    },
+];
+
+// Which domain each source is active in, going by what we delegated.
+// Every source starts out in the root domain.
+static mut SOURCE_DOMAIN: [u8; APLIC_NUM_SOURCES] = [0; APLIC_NUM_SOURCES];
+
+/// # Overview
+/// Get a domain by its index in the tree.
+pub fn aplic_domain(index: usize) -> &'static AplicDomain {
+    &APLIC_DOMAINS[index]
+}
+
+impl AplicDomain {
+    fn regs(&self) -> &Aplic {
+        unsafe { (self.base as *const Aplic).as_ref().unwrap() }
+    }
+
+    fn index(&self) -> usize {
+        APLIC_DOMAINS
+            .iter()
+            .position(|d| core::ptr::eq(d, self))
+            .unwrap()
+    }
+
+    /// # Overview
+    /// Find one of this domain's children by its child number.
+    /// # Returns
+    /// The child's index in the tree, `None` if there is no such child
+    pub fn child(&self, child: u32) -> Option<usize> {
+        let me = self.index();
+        (0..APLIC_DOMAINS.len())
+            .filter(|&i| APLIC_DOMAINS[i].parent == Some(me))
+            .nth(child as usize)
+    }
+
+    /// # Overview
+    /// Find the child number this domain uses for one of its children.
+    fn child_number(&self, index: usize) -> Option<u32> {
+        let me = self.index();
+        (0..APLIC_DOMAINS.len())
+            .filter(|&i| APLIC_DOMAINS[i].parent == Some(me))
+            .position(|i| i == index)
+            .map(|n| n as u32)
+    }
+
+    /// # Overview
+    /// Delegate a source to a child, after making sure that the child
+    /// exists and the source is ours to give. The hardware ignores a
+    /// delegation from a domain that doesn't have the source, and a bad
+    /// child number is WARL, so either would otherwise go unnoticed.
+    /// # Arguments
+    /// * `irq` - the source to delegate
+    /// * `child` - the child number to delegate it to
+    /// # Returns
+    /// `false` if the delegation is not allowed by the tree or the
+    /// hardware didn't take it
+    pub fn sourcecfg_delegate(&self, irq: u32, child: u32) -> bool {
+        let me = self.index();
+        let to = match self.child(child) {
+            Some(to) => to,
+            None => {
+                println!("APLIC domain {} has no child {}.", self.name, child);
+                return false;
+            }
+        };
+        let owner = unsafe { SOURCE_DOMAIN[irq as usize] } as usize;
+        if owner != me {
+            println!(
+                "APLIC IRQ {} is in domain {}, so domain {} can't delegate it.",
+                irq, APLIC_DOMAINS[owner].name, self.name
+            );
+            return false;
+        }
+        if !self.regs().sourcecfg_delegate(irq, child) {
+            return false;
+        }
+        unsafe {
+            SOURCE_DOMAIN[irq as usize] = to as u8;
+        }
+        true
+    }
+}
+
+/// # Overview
+/// Find the domain that handles a privilege mode. M mode is the root.
+/// S mode is the first S-level domain, which is the one our harts take
+/// S-mode interrupts from.
+fn domain_for(mode: AplicMode) -> usize {
+    APLIC_DOMAINS
+        .iter()
+        .position(|d| d.mode == mode)
+        .expect("No APLIC domain for this mode")
+}
+
+/// # Overview
+/// Make sure the domain tree makes sense: one M-level root that comes
+/// first, parents before children, and no M-level domain under an
+/// S-level one.
+/// # Returns
+/// `true` if the tree is sound
+fn aplic_check_tree() -> bool {
+    let mut ok = true;
+    for (i, d) in APLIC_DOMAINS.iter().enumerate() {
+        match d.parent {
+            None if i != 0 || d.mode != AplicMode::Machine => {
+                println!("APLIC domain {} can't be a root.", d.name);
+                ok = false;
+            }
+            Some(p) if p >= i => {
+                println!("APLIC domain {} comes before its parent.", d.name);
+                ok = false;
+            }
+            Some(p) if APLIC_DOMAINS[p].mode == AplicMode::Supervisor
+                && d.mode == AplicMode::Machine =>
+            {
+                println!("APLIC domain {} is M-level under an S-level parent.", d.name);
+                ok = false;
+            }
+            _ => {}
+        }
+    }
+    ok
+}
+
+/// # Overview
+/// Delegate a source down the tree from the root to a domain.
+/// # Arguments
+/// * `irq` - the source to delegate
+/// * `to` - the index of the domain that should end up with the source
+/// # Returns
+/// `false` if any step of the way failed
+pub fn aplic_delegate(irq: u32, to: usize) -> bool {
+    // Walk up from `to` to find the path, then delegate down it.
+    let mut path = [0usize; APLIC_DOMAINS.len()];
+    let mut depth = 0;
+    let mut d = to;
+    while let Some(parent) = APLIC_DOMAINS[d].parent {
+        path[depth] = d;
+        depth += 1;
+        d = parent;
+    }
+    for &child in path[..depth].iter().rev() {
+        let parent = aplic_domain(APLIC_DOMAINS[child].parent.unwrap());
+        let owner = unsafe { SOURCE_DOMAIN[irq as usize] } as usize;
+        if owner == child {
+            // Already delegated this far.
+            continue;
+        }
+        let number = parent.child_number(child).unwrap();
+        if !parent.sourcecfg_delegate(irq, number) {
+            return false;
+        }
+    }
+    true
+}
+
+/// # Overview
+/// Follow every source's delegation in the hardware, starting at the
+/// root, and compare where it ends up with where we delegated it.
+/// # Returns
+/// The number of sources that are not where they should be
+fn aplic_check_delegation() -> usize {
+    let mut bad = 0;
+    for irq in 1..APLIC_NUM_SOURCES as u32 {
+        let mut d = 0;
+        while let SourceCfg::Delegated(child) = APLIC_DOMAINS[d].regs().sourcecfg(irq) {
+            match APLIC_DOMAINS[d].child(child) {
+                Some(c) => d = c,
+                None => {
+                    println!(
+                        "APLIC IRQ {} is delegated to child {} of domain {}, which doesn't exist.",
+                        irq, child, APLIC_DOMAINS[d].name
+                    );
+                    bad += 1;
+                    break;
+                }
+            }
+        }
+        let owner = unsafe { SOURCE_DOMAIN[irq as usize] } as usize;
+        if d != owner {
+            println!(
+                "APLIC IRQ {} is in domain {}, but should be in domain {}.",
+                irq, APLIC_DOMAINS[d].name, APLIC_DOMAINS[owner].name
+            );
+            bad += 1;
         }
     }
+    bad
+}
+
+/// # Overview
+/// Write an APLIC register and read it back. Most APLIC registers are
+/// WARL, so a value the domain doesn't accept is silently changed.
+/// # Returns
+/// `true` if the register took the value, `false` if it did not, in which
+/// case the difference is printed
+fn write_check(reg: &Volatile<u32>, val: u32, name: &str, irq: u32) -> bool {
+    reg.write(val);
+    let got = reg.read();
+    if got != val {
+        println!("APLIC {}[{}]: wrote 0x{:08x}, read back 0x{:08x}.", name, irq, val, got);
+        return false;
+    }
+    true
 }
 
 /// Interrupt Delivery Control is only used in 'direct' mode
 #[repr(C)]
 struct InterruptDeliveryControl {
-    pub idelivery: u32,
-    pub iforce: u32,
-    pub ithreshold: u32,
-    pub topi: u32,
-    pub claimi: u32,
+    pub idelivery: Volatile<u32>,
+    pub iforce: Volatile<u32>,
+    pub ithreshold: Volatile<u32>,
+    // 0x0C to 0x17 are reserved, putting topi at 0x18 and claimi at 0x1C
+    _reserved: [u32; 3],
+    pub topi: Volatile<u32>,
+    pub claimi: Volatile<u32>,
 }
 
 #[allow(dead_code)]
@@ -220,65 +674,519 @@ impl InterruptDeliveryControl {
     /// `hart` - the HART number for the IDC to get
     /// # Returns
     /// A mutable MMIO pointer to the IDC registers
-    const fn ptr(hart: usize) -> *mut Self {
+    fn ptr(hart: usize) -> *mut Self {
         assert!(hart < 1024);
-        (APLIC_S_IDC + hart * 32) as *mut Self
+        let base = aplic_domain(domain_for(AplicMode::Supervisor)).base;
+        (base + APLIC_IDC_OFFSET + hart * 32) as *mut Self
     }
 
     /// # Overview
-    /// Get an immutable reference to the IDC registers
+    /// Get a reference to the IDC registers
     /// # Arguments
     /// `hart` - the HART number for the IDC to get
     /// # Returns
-    /// An immutable reference to the IDC area
+    /// A reference to the IDC area
     pub fn as_ref<'a>(hart: usize) -> &'a Self {
         unsafe { Self::ptr(hart).as_ref().unwrap() }
     }
 
     /// # Overview
-    /// Get a mutable reference to the IDC registers
+    /// Turn on delivery to this hart.
     /// # Arguments
-    /// `hart` - the HART number for the IDC to get
+    /// * `threshold` - only priorities numerically lower than this are
+    ///   delivered. 0 lets every priority through.
+    pub fn enable(&self, threshold: u32) {
+        self.iforce.write(0);
+        self.ithreshold.write(threshold);
+        self.idelivery.write(1);
+    }
+
+    /// # Overview
+    /// Find the lowest priority the APLIC implements. Priorities are WARL
+    /// with as many bits as the APLIC likes, and ithreshold has the same
+    /// number of bits, so write all 1s and see what sticks. This leaves
+    /// ithreshold at 0.
+    /// # Returns
+    /// The lowest (numerically largest) priority, 1 to 255
+    pub fn priority_max(&self) -> u32 {
+        self.ithreshold.write(0xFF);
+        let max = self.ithreshold.read() & 0xFF;
+        self.ithreshold.write(0);
+        max.max(1)
+    }
+
+    /// # Overview
+    /// Claim the highest priority pending interrupt, which also clears
+    /// its pending bit.
     /// # Returns
-    /// A mutable reference to the IDC area
-    pub fn as_mut<'a>(hart: usize) -> &'a mut Self {
-        unsafe { Self::ptr(hart).as_mut().unwrap() }
+    /// The interrupt source number, or 0 if nothing was pending
+    pub fn claim(&self) -> u32 {
+        // claimi has the source in bits 25:16 and its priority in 7:0
+        self.claimi.read() >> 16 & 0x3FF
     }
 }
 
+/// A handler for a source in direct mode. The argument is the `data` value
+/// given when the handler was registered.
+pub type AplicHandler = fn(usize);
+
+#[derive(Clone, Copy)]
+struct DirectHandler {
+    handler: AplicHandler,
+    data: usize,
+}
+
+// Set by aplic_init() when the APLIC is to deliver interrupts through the
+// IDCs instead of sending MSIs.
+static mut DIRECT_MODE: bool = false;
+
+// The lowest priority a direct-mode target can have, found by aplic_init()
+static mut DIRECT_PRIO_MAX: u32 = 1;
+
+// In MSI mode, the IMSIC calls the handler for an EIID. In direct mode, we
+// claim the source from the IDC and look up its handler here.
+static mut DIRECT_HANDLERS: [Option<DirectHandler>; APLIC_NUM_SOURCES] =
+    [None; APLIC_NUM_SOURCES];
+
 /// # Overview
-/// Intiailize the APLIC system and run a test, including
-/// setting up the APLIC to send messages to the IMSIC in
-/// supervisor mode.
-pub fn aplic_init() {
+/// See which delivery mode `aplic_init` set up.
+/// # Returns
+/// `true` if interrupts are delivered through the IDCs, `false` if they
+/// are sent as MSIs to the IMSICs
+pub fn aplic_is_direct() -> bool {
+    unsafe { DIRECT_MODE }
+}
+
+/// # Overview
+/// Turn on a hart's S-mode interrupt delivery control. Only used in
+/// direct mode.
+/// # Arguments
+/// * `hart` - the hart whose IDC to enable
+pub fn aplic_idc_init(hart: usize) {
+    assert!(hart < MAX_HARTS);
+    InterruptDeliveryControl::as_ref(hart).enable(0);
+}
+
+/// # Overview
+/// Intiailize the APLIC system so that every domain in the tree is
+/// enabled and sources can be delegated to the S domain, which either
+/// sends messages to the IMSIC in supervisor mode or interrupts the harts
+/// directly. Sources are added with `aplic_request`.
+/// # Arguments
+/// * `direct` - `true` to use direct delivery, `false` to send MSIs
+pub fn aplic_init(direct: bool) {
+    if !aplic_check_tree() {
+        println!("APLIC domain tree is broken.");
+        return;
+    }
     // The root APLIC
-    let mplic = Aplic::as_mut(AplicMode::Machine);
-    // The delgated child APLIC
-    let splic = Aplic::as_mut(AplicMode::Supervisor);
+    let mplic = Aplic::as_ref(AplicMode::Machine);
 
-    // Enable both the machine and supervisor PLICS
-    mplic.set_domaincfg(false, true, true);
-    splic.set_domaincfg(false, true, true);
+    // Enable every domain
+    unsafe {
+        DIRECT_MODE = direct;
+    }
+    for domain in APLIC_DOMAINS.iter() {
+        domain.regs().set_domaincfg(false, !direct, true);
+    }
+    // DM is WARL, so an APLIC that can only do one of the two modes
+    // ignores what we wrote.
+    if mplic.msimode() == direct {
+        let mode = if direct { "direct" } else { "MSI" };
+        println!("APLIC does not support {} mode. Check QEMU's aia= setting.", mode);
+    }
 
-    // Write messages to IMSIC_S
-    mplic.set_msiaddr(AplicMode::Supervisor, crate::imsic::IMSIC_S);
+    if direct {
+        unsafe {
+            DIRECT_PRIO_MAX = InterruptDeliveryControl::as_ref(0).priority_max();
+        }
+        aplic_idc_init(0);
+    } else {
+        // Write messages to IMSIC_S
+        mplic.set_msiaddr(AplicMode::Supervisor, crate::imsic::IMSIC_S);
+    }
+}
 
-    // Delegate interrupt 10 to child 0, which is APLIC_S
-    // Interrupt 10 is the UART. So, whenever the UART receives something
-    // into its receiver buffer register, it triggers an IRQ #10 to the APLIC.
-    mplic.sourcecfg_delegate(10, 0);
+/// # Overview
+/// Route a level-high wired source to a hart's S mode and call a handler
+/// when it fires, in whichever mode the APLIC is in. In MSI mode, the
+/// source sends an EIID equal to its IRQ number. In direct mode, the IRQ
+/// number is also its priority (lower is more urgent), except that sources
+/// past the lowest priority the APLIC implements all share it. The source
+/// is left disabled.
+/// # Arguments
+/// * `irq` - the wired interrupt source
+/// * `hart` - the hart to interrupt
+/// * `handler` - called with `data` when the source fires
+/// # Returns
+/// `false` if the EIID or source already has a handler, or the source
+/// could not be routed
+pub fn aplic_request(irq: u32, hart: usize, handler: AplicHandler, data: usize) -> bool {
+    if aplic_is_direct() {
+        let slot = unsafe { &mut DIRECT_HANDLERS[irq as usize] };
+        if slot.is_some() {
+            return false;
+        }
+        *slot = Some(DirectHandler { handler, data });
+        if !aplic_route_direct(irq, hart as u32, irq.min(unsafe { DIRECT_PRIO_MAX })) {
+            *slot = None;
+            return false;
+        }
+    } else {
+        // The EIID is the value that is written to the MSI address
+        // When we read TOPEI in IMSIC, it will give us the EIID if it
+        // has been enabled.
+        if !imsic_reserve(hart, PrivMode::Supervisor, irq) {
+            return false;
+        }
+        imsic_register(hart, PrivMode::Supervisor, irq, handler, data);
+        if !imsic_enable_on(hart, PrivMode::Supervisor, irq) {
+            imsic_free(hart, PrivMode::Supervisor, irq);
+            return false;
+        }
+        if !aplic_route_msi(irq, hart as u32, irq) {
+            imsic_disable_on(hart, PrivMode::Supervisor, irq);
+            imsic_free(hart, PrivMode::Supervisor, irq);
+            return false;
+        }
+    }
+    true
+}
 
-    // The EIID is the value that is written to the MSI address
-    // When we read TOPEI in IMSIC, it will give us the EIID if it
-    // has been enabled.
-    splic.set_target_msi(10, 0, 0, 10);
+/// # Overview
+/// Route a level-high wired source through the S domain so that it sends
+/// an MSI to a hart's S-mode interrupt file. The source is left disabled.
+/// # Arguments
+/// * `irq` - the wired interrupt source
+/// * `hart` - the hart to send the MSI to
+/// * `eiid` - the EIID to send, which the caller must have reserved
+/// # Returns
+/// `false` if the source could not be delegated to the S domain or
+/// didn't take its mode or target
+pub fn aplic_route_msi(irq: u32, hart: u32, eiid: u32) -> bool {
+    let splic = Aplic::as_ref(AplicMode::Supervisor);
+
+    // Delegate the interrupt down to the S domain
+    if !aplic_delegate(irq, domain_for(AplicMode::Supervisor)) {
+        return false;
+    }
 
     // Level high means to trigger the message delivery when the IRQ is
-    // asserted (high).
-    splic.set_sourcecfg(10, SourceModes::LevelHigh);
+    // asserted (high). The target of an inactive source is read-only
+    // zero, so this has to come first.
+    splic.set_sourcecfg(irq, SourceModes::LevelHigh) && splic.set_target_msi(irq, hart, 0, eiid)
+}
+
+/// # Overview
+/// Route a level-high wired source through the S domain so that it
+/// interrupts a hart directly through its IDC. The source is left disabled.
+/// # Arguments
+/// * `irq` - the wired interrupt source
+/// * `hart` - the hart to interrupt
+/// * `prio` - the priority, from 1 (highest) to however many the APLIC
+///   implements
+/// # Returns
+/// `false` if the source could not be delegated to the S domain or
+/// didn't take its mode or target
+pub fn aplic_route_direct(irq: u32, hart: u32, prio: u32) -> bool {
+    let splic = Aplic::as_ref(AplicMode::Supervisor);
+
+    if !aplic_delegate(irq, domain_for(AplicMode::Supervisor)) {
+        return false;
+    }
+    splic.set_sourcecfg(irq, SourceModes::LevelHigh) && splic.set_target_direct(irq, hart, prio)
+}
 
+/// # Overview
+/// Enable or disable a source routed with `aplic_route_msi` or
+/// `aplic_route_direct`.
+pub fn aplic_set_enabled(irq: u32, enabled: bool) {
     // The order is important. QEMU will not allow enabling of the IRQ
     // unless the source configuration is set properly.
-    // mplic.set_irq(10, true);
-    splic.set_ie(10, true);
+    Aplic::as_ref(AplicMode::Supervisor).set_ie(irq, enabled);
+}
+
+/// # Overview
+/// Handle a direct mode S-mode external interrupt. Called from
+/// `trap::rust_trap`.
+pub fn aplic_handle() {
+    let hart = csr_read!("mhartid");
+    match InterruptDeliveryControl::as_ref(hart).claim() {
+        0 => println!("Spurious APLIC interrupt."),
+        irq => match unsafe { DIRECT_HANDLERS[irq as usize] } {
+            Some(action) => (action.handler)(action.data),
+            None => println!("Unknown APLIC IRQ #{}", irq),
+        },
+    }
+}
+
+/// # Overview
+/// Move a source added with `aplic_request` to another hart without
+/// losing an edge. The source is masked while it moves, and an edge that
+/// comes in while it is masked stays pending in the APLIC until it is
+/// unmasked. In direct mode, that is all it takes. In MSI mode, an MSI
+/// already sent to the old hart may not have arrived yet, so we send a
+/// genmsi behind it, and if it is then pending on the old hart's file,
+/// it is sent again to the new one.
+/// # Arguments
+/// * `irq` - the wired interrupt source
+/// * `hart` - the hart to move it to
+/// # Returns
+/// `false` if the source could not be moved. It is left where it was.
+pub fn aplic_set_affinity(irq: u32, hart: usize) -> bool {
+    assert!(hart < MAX_HARTS);
+    let splic = Aplic::as_ref(AplicMode::Supervisor);
+    let enabled = splic.ie(irq);
+    match splic.target(irq) {
+        Target::Direct { hart: old, prio } => {
+            if old as usize == hart {
+                return true;
+            }
+            aplic_idc_init(hart);
+            splic.set_ie(irq, false);
+            let moved = splic.set_target_direct(irq, hart as u32, prio);
+            if !moved {
+                splic.set_target_direct(irq, old, prio);
+            }
+            if enabled {
+                splic.set_ie(irq, true);
+            }
+            moved
+        }
+        Target::Msi { hart: old, eiid, .. } => {
+            let old = old as usize;
+            if old == hart {
+                return true;
+            }
+            // The source keeps its EIID, so the new hart needs that EIID.
+            if !imsic_reserve(hart, PrivMode::Supervisor, eiid) {
+                println!("EIID {} is already taken on hart {}.", eiid, hart);
+                return false;
+            }
+            if !imsic_copy_handler(old, hart, PrivMode::Supervisor, eiid, eiid) {
+                imsic_free(hart, PrivMode::Supervisor, eiid);
+                return false;
+            }
+            splic.set_ie(irq, false);
+            // EIID 0 is never delivered, so the genmsi only tells us that
+            // every MSI this domain sent to the old hart has landed. If we
+            // can't be sure of that, an edge could be lost, so go back.
+            let moved = splic.set_target_msi(irq, hart as u32, 0, eiid)
+                && splic.genmsi(old as u32, 0);
+            if !moved {
+                println!("Couldn't move APLIC source {} to hart {}.", irq, hart);
+                splic.set_target_msi(irq, old as u32, 0, eiid);
+                if enabled {
+                    splic.set_ie(irq, true);
+                }
+                imsic_disable_on(hart, PrivMode::Supervisor, eiid);
+                imsic_free(hart, PrivMode::Supervisor, eiid);
+                return false;
+            }
+            if imsic_take_pending(old, PrivMode::Supervisor, eiid) {
+                imsic_send(hart, PrivMode::Supervisor, eiid);
+            }
+            if enabled {
+                splic.set_ie(irq, true);
+            }
+            imsic_disable_on(old, PrivMode::Supervisor, eiid);
+            imsic_free(old, PrivMode::Supervisor, eiid);
+            true
+        }
+    }
+}
+
+/// # Overview
+/// Send an MSI through a domain's genmsi register.
+/// # Arguments
+/// * `mode` - the domain, which is also the mode of the interrupt file
+/// * `hart` - the hart to send the MSI to
+/// * `eiid` - the identity to send
+/// # Returns
+/// `false` if the APLIC is in direct mode or genmsi stayed busy
+pub fn aplic_genmsi(mode: AplicMode, hart: u32, eiid: u32) -> bool {
+    if aplic_is_direct() {
+        println!("genmsi needs the APLIC to be in MSI mode.");
+        return false;
+    }
+    if !Aplic::as_ref(mode).genmsi(hart, eiid) {
+        println!("APLIC genmsi is stuck busy.");
+        return false;
+    }
+    true
+}
+
+// Set by genmsi_test_handler when the test MSI comes in
+static mut GENMSI_RECEIVED: bool = false;
+
+fn genmsi_test_handler(eiid: usize) {
+    println!("genmsi EIID {} received.", eiid);
+    unsafe {
+        GENMSI_RECEIVED = true;
+    }
+}
+
+/// # Overview
+/// Send an MSI to this hart's S-mode interrupt file from the S domain's
+/// genmsi and wait for it to come in. This tests the APLIC to IMSIC path
+/// without needing a wired source. This is the `genmsi` console command.
+pub fn aplic_genmsi_test() {
+    if aplic_is_direct() {
+        println!("genmsi needs the APLIC to be in MSI mode.");
+        return;
+    }
+    let hart = csr_read!("mhartid");
+    let eiid = match imsic_alloc(hart, PrivMode::Supervisor) {
+        Some(eiid) => eiid,
+        None => {
+            println!("No free S-mode EIIDs.");
+            return;
+        }
+    };
+    imsic_register(hart, PrivMode::Supervisor, eiid, genmsi_test_handler, eiid as usize);
+    imsic_enable(PrivMode::Supervisor, eiid as usize);
+    unsafe {
+        GENMSI_RECEIVED = false;
+    }
+    println!("Sending EIID {} to hart {} through genmsi.", eiid, hart);
+    if aplic_genmsi(AplicMode::Supervisor, hart as u32, eiid) {
+        let received = wait_until(|| unsafe { read_volatile(addr_of!(GENMSI_RECEIVED)) });
+        if !received {
+            println!("genmsi EIID {} never came in.", eiid);
+        }
+    }
+    imsic_disable(PrivMode::Supervisor, eiid as usize);
+    imsic_free(hart, PrivMode::Supervisor, eiid);
+}
+
+// The hart that AFFINITY_TEST_IRQ came in on, usize::MAX until it does
+static mut AFFINITY_HART: usize = usize::MAX;
+
+fn affinity_test_handler(_: usize) {
+    let hart = csr_read!("mhartid");
+    unsafe {
+        AFFINITY_HART = hart;
+    }
+}
+
+/// # Overview
+/// Route a spare source to this hart, move it to another hart that is up,
+/// then make it pending and see which hart it comes in on. This tests
+/// that the APLIC reaches a hart other than 0 after a move. This is the
+/// `affinity` console command.
+pub fn aplic_affinity_test() {
+    let me = csr_read!("mhartid");
+    let hart = match (0..MAX_HARTS).find(|&h| h != me && hart_online(h)) {
+        Some(hart) => hart,
+        None => {
+            println!("affinity needs another hart to be up.");
+            return;
+        }
+    };
+    let irq = AFFINITY_TEST_IRQ;
+    if !aplic_request(irq, me, affinity_test_handler, 0) {
+        println!("Couldn't route APLIC source {}.", irq);
+        return;
+    }
+    let splic = Aplic::as_ref(AplicMode::Supervisor);
+    // Nothing drives the wire, so make the source edge triggered, which
+    // lets setipnum make it pending.
+    let moved =
+        splic.set_sourcecfg(irq, SourceModes::RisingEdge) && aplic_set_affinity(irq, hart);
+    if moved {
+        unsafe {
+            AFFINITY_HART = usize::MAX;
+        }
+        aplic_set_enabled(irq, true);
+        println!("Raising APLIC source {} after moving it to hart {}.", irq, hart);
+        splic.setipnum.write(irq);
+        let arrived =
+            wait_until(|| unsafe { read_volatile(addr_of!(AFFINITY_HART)) } != usize::MAX);
+        match unsafe { AFFINITY_HART } {
+            _ if !arrived => println!("APLIC source {} never came in.", irq),
+            h if h == hart => println!("APLIC source {} came in on hart {}.", irq, h),
+            h => println!("APLIC source {} came in on hart {}, not {}.", irq, h, hart),
+        }
+    }
+    aplic_set_enabled(irq, false);
+    splic.set_sourcecfg(irq, SourceModes::Inactive);
+    // The handler is wherever the move left it.
+    let owner = if moved { hart } else { me };
+    if aplic_is_direct() {
+        unsafe {
+            DIRECT_HANDLERS[irq as usize] = None;
+        }
+    } else {
+        imsic_disable_on(owner, PrivMode::Supervisor, irq);
+        imsic_free(owner, PrivMode::Supervisor, irq);
+    }
+}
+
+/// # Overview
+/// Print one domain's configuration and every source that is active or
+/// delegated in it.
+fn aplic_dump_domain(domain: &AplicDomain) {
+    let aplic = domain.regs();
+    let cfg = aplic.domaincfg.read();
+    let level = match domain.mode {
+        AplicMode::Machine => "M",
+        AplicMode::Supervisor => "S",
+    };
+    print!("Domain {} ({}-level) @ 0x{:08x}", domain.name, level, domain.base);
+    if let Some(parent) = domain.parent {
+        let number = aplic_domain(parent).child_number(domain.index()).unwrap();
+        print!(", child {} of {}", number, aplic_domain(parent).name);
+    }
+    println!(
+        ": domaincfg 0x{:08x} (IE={} DM={} BE={})",
+        cfg,
+        cfg >> 8 & 1,
+        cfg >> 2 & 1,
+        cfg & 1
+    );
+    if domain.parent.is_none() {
+        println!(
+            "  mmsiaddrcfg 0x{:08x}:{:08x}, smsiaddrcfg 0x{:08x}:{:08x}",
+            aplic.mmsiaddrcfgh.read(),
+            aplic.mmsiaddrcfg.read(),
+            aplic.smsiaddrcfgh.read(),
+            aplic.smsiaddrcfg.read()
+        );
+    }
+    for irq in 1..APLIC_NUM_SOURCES as u32 {
+        let cfg = aplic.sourcecfg(irq);
+        match cfg {
+            SourceCfg::Mode(SourceModes::Inactive) => continue,
+            SourceCfg::Delegated(child) => {
+                match domain.child(child) {
+                    Some(c) => println!("  IRQ {:4}: delegated to {}", irq, aplic_domain(c).name),
+                    None => println!("  IRQ {:4}: delegated to missing child {}", irq, child),
+                }
+                continue;
+            }
+            SourceCfg::Mode(m) => print!("  IRQ {:4}: {:12}", irq, m.name()),
+            SourceCfg::Reserved(sm) => print!("  IRQ {:4}: reserved SM {}", irq, sm),
+        }
+        match aplic.target(irq) {
+            Target::Msi { hart, guest, eiid } => {
+                print!(" -> hart {} guest {} EIID {:3}", hart, guest, eiid)
+            }
+            Target::Direct { hart, prio } => print!(" -> hart {} prio {:3}", hart, prio),
+        }
+        println!(" IE={} IP={}", u32::from(aplic.ie(irq)), u32::from(aplic.ip(irq)));
+    }
+}
+
+/// # Overview
+/// Print the routing table of every domain, then check that every source
+/// is in the domain we delegated it to. This is the `aplic` console
+/// command.
+pub fn aplic_dump() {
+    for domain in APLIC_DOMAINS.iter() {
+        aplic_dump_domain(domain);
+    }
+    match aplic_check_delegation() {
+        0 => println!("Every source is where it was delegated."),
+        bad => println!("{} sources are mis-delegated.", bad),
+    }
 }
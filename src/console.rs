@@ -1,7 +1,11 @@
 use crate::{
+    ipi::ipi_test,
+    irq::controller,
     page::pages_remaining,
-    pci::pci_init,
+    pci::{lspci, pci_init},
+    pcieport,
     ringbuffer::{RingBuffer, RING_BUFFER_SIZE},
+    timer::timer_ms,
     nvme
 };
 use core::{
@@ -22,6 +26,10 @@ const UART_FCR: usize = 2;
 const UART_LCR: usize = 3;
 const UART_LSR: usize = 5;
 
+// The UART is wired to IRQ 10 in virt.c. With the IMSIC, we use the same
+// number as its EIID so it is easy to cross-reference.
+const UART_IRQ: u32 = 10;
+
 /// Write to a UART register. There are no safety checks! So,
 /// make sure you only use the UART_XXYYZZ registers for reg.
 fn uart_write(reg: usize, val: u8) {
@@ -45,6 +53,17 @@ pub fn uart_init() {
     uart_write(UART_ICR, 1);
 }
 
+/// Send the UART's receive interrupts to hart 0. This has to wait until
+/// the interrupt controller is up, which is after uart_init() since we
+/// want to print while setting it up.
+pub fn uart_irq_init() {
+    if !controller().request(UART_IRQ, 0, |_| console_irq(), 0) {
+        println!("UART IRQ {} is already taken.", UART_IRQ);
+        return;
+    }
+    controller().set_enabled(UART_IRQ, true);
+}
+
 pub struct Uart;
 impl Uart {
     pub fn read_char(&mut self) -> Option<u8> {
@@ -84,6 +103,36 @@ pub fn console_irq() {
     }
 }
 
+#[cfg(any(feature = "aia-msi", feature = "aia-direct"))]
+fn aplic_dump() {
+    crate::aplic::aplic_dump();
+}
+
+#[cfg(any(feature = "aia-msi", feature = "aia-direct"))]
+fn genmsi_test() {
+    crate::aplic::aplic_genmsi_test();
+}
+
+#[cfg(any(feature = "aia-msi", feature = "aia-direct"))]
+fn affinity_test() {
+    crate::aplic::aplic_affinity_test();
+}
+
+#[cfg(feature = "plic")]
+fn aplic_dump() {
+    println!("This kernel was built for the PLIC.");
+}
+
+#[cfg(feature = "plic")]
+fn genmsi_test() {
+    println!("This kernel was built for the PLIC.");
+}
+
+#[cfg(feature = "plic")]
+fn affinity_test() {
+    println!("This kernel was built for the PLIC.");
+}
+
 fn prompt() {
     print!("\n> ");
 }
@@ -117,10 +166,35 @@ fn runcmd(buffer: &[u8]) {
     } else if strequals(buffer, b"help") {
         println!("Commands: ");
         println!("  pages    - How many pages are remaining?");
+        println!("  uptime   - How long since the machine started?");
+        println!("  aplic    - Show the APLIC routing table");
+        println!("  genmsi   - Send a test MSI through the APLIC's genmsi");
+        println!("  affinity - Move an APLIC source to another hart and raise it");
+        println!("  ipi      - Call a function on every other hart");
         println!("  pci      - Start PCI");
+        println!("  lspci    - List PCI functions (lspci -v to dump config space)");
+        println!("  nvme     - Start NVMe (run pci first)");
+        println!("  nvmeinfo - Show NVMe controllers and namespaces");
         println!("  quit     - Quit");
+    } else if strequals(buffer, b"genmsi") {
+        genmsi_test();
+    } else if strequals(buffer, b"affinity") {
+        affinity_test();
+    } else if strequals(buffer, b"uptime") {
+        let ms = timer_ms();
+        println!("Up {}.{:03} seconds.", ms / 1000, ms % 1000);
+    } else if strequals(buffer, b"ipi") {
+        ipi_test();
+    } else if strequals(buffer, b"aplic") {
+        aplic_dump();
+    } else if strequals(buffer, b"lspci -v") {
+        lspci(true);
+    } else if strequals(buffer, b"lspci") {
+        lspci(false);
     } else if strequals(buffer, b"pci") {
         pci_init();
+    } else if strequals(buffer, b"nvmeinfo") {
+        nvme::info();
     } else if strequals(buffer, b"nvme") {
         nvme::init();
     } else {
@@ -178,7 +252,9 @@ pub fn run() {
                 print!("{}", c_as_char)
             }
         } else {
-            // There was nothing to grab, wait for an interrupt
+            // There was nothing to grab, so do any hot-plug work the
+            // interrupts left for us, then wait for an interrupt
+            pcieport::poll();
             unsafe {
                 asm!("wfi");
             }
@@ -1,10 +1,10 @@
 #![allow(dead_code)]
 
-use crate::console::console_irq;
+use crate::{ipi::ipi_call, MAX_HARTS};
 use core::{arch::asm, ptr::write_volatile};
 
 // Each hart is a page away from each other (4096 bytes or 0x1000)
-const IMSIC_HART_STRIDE: usize = 0x1000;
+pub const IMSIC_HART_STRIDE: usize = 0x1000;
 
 // There are two IMSICs per HART
 //   one for machine mode (M)
@@ -15,11 +15,11 @@ pub const IMSIC_S: usize = 0x2800_0000;
 // Helper functions for determining MMIO address
 // for the messages. Each HART has an M and S mode
 // IMSIC. Each HART has its own IMSIC in its own page.
-const fn imsic_m(hart: usize) -> usize {
+pub const fn imsic_m(hart: usize) -> usize {
     IMSIC_M + IMSIC_HART_STRIDE * hart
 }
 
-const fn imsic_s(hart: usize) -> usize {
+pub const fn imsic_s(hart: usize) -> usize {
     IMSIC_S + IMSIC_HART_STRIDE * hart
 }
 
@@ -64,6 +64,12 @@ const EITHRESHOLD: usize = 0x72;
 const EIP: usize = 0x80;
 const EIE: usize = 0xC0;
 
+// QEMU's virt machine implements 255 interrupt identities per
+// interrupt file (1..=255). Identity 0 is never delivered since
+// TOPEI uses it to mean "nothing pending".
+pub const IMSIC_NUM_IDS: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum PrivMode {
     Machine = 0,
     Supervisor = 1,
@@ -118,7 +124,7 @@ fn imsic_read(reg: usize) -> usize {
 }
 
 // Enable a message number
-fn imsic_enable(mode: PrivMode, which: usize) {
+pub fn imsic_enable(mode: PrivMode, which: usize) {
     let eiebyte = EIE + XLEN_STRIDE * which / XLEN;
     let bit = which % XLEN;
 
@@ -136,7 +142,7 @@ fn imsic_enable(mode: PrivMode, which: usize) {
     };
 }
 
-fn imsic_disable(mode: PrivMode, which: usize) {
+pub fn imsic_disable(mode: PrivMode, which: usize) {
     let eiebyte = EIE + XLEN_STRIDE * which / XLEN;
     let bit = which % XLEN;
 
@@ -190,8 +196,286 @@ fn imsic_clear(mode: PrivMode, which: usize) {
     };
 }
 
-pub fn imsic_init() {
-    let hartid = csr_read!("mhartid");
+/// A handler for a message. The argument is the `data` value given
+/// when the handler was registered, so one function can serve several
+/// EIIDs (e.g., one per queue).
+pub type MsiHandler = fn(usize);
+
+#[derive(Clone, Copy)]
+struct MsiVector {
+    allocated: bool,
+    handler: Option<MsiHandler>,
+    data: usize,
+}
+
+impl MsiVector {
+    const fn new() -> Self {
+        Self {
+            allocated: false,
+            handler: None,
+            data: 0,
+        }
+    }
+}
+
+// Every hart has an M and S interrupt file and each file has its own
+// set of identities, so vectors are tracked per hart and per mode.
+static mut MSI_VECTORS: [[[MsiVector; IMSIC_NUM_IDS]; 2]; MAX_HARTS] =
+    [[[MsiVector::new(); IMSIC_NUM_IDS]; 2]; MAX_HARTS];
+
+fn msi_vector<'a>(hart: usize, mode: PrivMode, eiid: u32) -> &'a mut MsiVector {
+    assert!(hart < MAX_HARTS && eiid > 0 && (eiid as usize) < IMSIC_NUM_IDS);
+    unsafe { &mut MSI_VECTORS[hart][mode as usize][eiid as usize] }
+}
+
+/// # Overview
+/// Allocate a free EIID on a hart's interrupt file.
+/// Lower EIIDs have higher priority, so this hands out the
+/// lowest numbered identity that is still free.
+/// # Arguments
+/// * `hart` - the hart whose interrupt file to allocate from
+/// * `mode` - the machine or supervisor interrupt file
+/// # Returns
+/// `Some(eiid)` if one was free, `None` otherwise
+pub fn imsic_alloc(hart: usize, mode: PrivMode) -> Option<u32> {
+    for eiid in 1..IMSIC_NUM_IDS as u32 {
+        let vec = msi_vector(hart, mode, eiid);
+        if !vec.allocated {
+            vec.allocated = true;
+            return Some(eiid);
+        }
+    }
+    None
+}
+
+/// # Overview
+/// Allocate a block of consecutive EIIDs whose first EIID is a multiple
+/// of the block size. Multi-message MSI needs this since the device
+/// ORs the vector number into the low bits of the message data.
+/// # Arguments
+/// * `hart` - the hart whose interrupt file to allocate from
+/// * `mode` - the machine or supervisor interrupt file
+/// * `count` - the number of EIIDs. This must be a power of two.
+/// # Returns
+/// `Some(first eiid)` if a block was free, `None` otherwise
+pub fn imsic_alloc_block(hart: usize, mode: PrivMode, count: usize) -> Option<u32> {
+    assert!(count.is_power_of_two());
+    // EIID 0 can never be used, so a block of 1 starts at 1, but any
+    // bigger block has to start at the next multiple of its size.
+    let mut first = count.max(1);
+    while first + count <= IMSIC_NUM_IDS {
+        let free =
+            (first..first + count).all(|eiid| !msi_vector(hart, mode, eiid as u32).allocated);
+        if free {
+            for eiid in first..first + count {
+                msi_vector(hart, mode, eiid as u32).allocated = true;
+            }
+            return Some(first as u32);
+        }
+        first += count;
+    }
+    None
+}
+
+/// # Overview
+/// Reserve a specific EIID. This is for sources that have their EIID
+/// decided elsewhere (such as the hard-coded test messages).
+/// # Returns
+/// `true` if the EIID was free and is now reserved, `false` if it
+/// was already taken.
+pub fn imsic_reserve(hart: usize, mode: PrivMode, eiid: u32) -> bool {
+    let vec = msi_vector(hart, mode, eiid);
+    if vec.allocated {
+        false
+    } else {
+        vec.allocated = true;
+        true
+    }
+}
+
+/// # Overview
+/// Release an EIID and drop its handler. This does not disable
+/// the EIID since EIE can only be written by the hart that owns it.
+pub fn imsic_free(hart: usize, mode: PrivMode, eiid: u32) {
+    *msi_vector(hart, mode, eiid) = MsiVector::new();
+}
+
+/// # Overview
+/// Bind a handler to an allocated EIID. The handler is called
+/// from `imsic_handle` when the EIID is popped from TOPEI.
+/// # Arguments
+/// * `hart`, `mode`, `eiid` - the vector to bind
+/// * `handler` - the function to call
+/// * `data` - the argument to give `handler`
+/// # Returns
+/// `false` if the EIID has not been allocated or reserved
+pub fn imsic_register(
+    hart: usize,
+    mode: PrivMode,
+    eiid: u32,
+    handler: MsiHandler,
+    data: usize,
+) -> bool {
+    let vec = msi_vector(hart, mode, eiid);
+    if !vec.allocated {
+        return false;
+    }
+    vec.handler = Some(handler);
+    vec.data = data;
+    true
+}
+
+/// # Overview
+/// Look up the handler bound to an EIID.
+/// # Returns
+/// The handler and its data, or `None` if nothing is bound
+pub fn imsic_handler(hart: usize, mode: PrivMode, eiid: u32) -> Option<(MsiHandler, usize)> {
+    let vec = msi_vector(hart, mode, eiid);
+    vec.handler.map(|handler| (handler, vec.data))
+}
+
+/// # Overview
+/// Bind an EIID's handler to an EIID on another hart and enable it there.
+/// This is the first half of moving a vector. The new EIID must already
+/// be allocated or reserved.
+/// # Returns
+/// `false` if the old EIID has no handler or the new one can't be used
+pub fn imsic_copy_handler(from: usize, to: usize, mode: PrivMode, old: u32, new: u32) -> bool {
+    match imsic_handler(from, mode, old) {
+        Some((handler, data)) => {
+            imsic_register(to, mode, new, handler, data) && imsic_enable_on(to, mode, new)
+        }
+        None => false,
+    }
+}
+
+// The mode and EIID packed into one argument for an `ipi_call`
+fn call_arg(mode: PrivMode, eiid: u32) -> usize {
+    (mode as usize) << 16 | eiid as usize
+}
+
+fn call_unpack(arg: usize) -> (PrivMode, usize) {
+    let mode = match arg >> 16 {
+        0 => PrivMode::Machine,
+        _ => PrivMode::Supervisor,
+    };
+    (mode, arg & 0xFFFF)
+}
+
+fn enable_call(arg: usize) -> usize {
+    let (mode, which) = call_unpack(arg);
+    imsic_enable(mode, which);
+    0
+}
+
+fn disable_call(arg: usize) -> usize {
+    let (mode, which) = call_unpack(arg);
+    imsic_disable(mode, which);
+    0
+}
+
+fn take_pending_call(arg: usize) -> usize {
+    let (mode, which) = call_unpack(arg);
+    take_pending(mode, which) as usize
+}
+
+/// # Overview
+/// Enable an EIID on any hart's interrupt file. EIE is only reachable
+/// through the CSRs of the hart that owns the file, so another hart is
+/// asked to do it with an IPI.
+/// # Returns
+/// `false` if the hart could not be reached
+pub fn imsic_enable_on(hart: usize, mode: PrivMode, eiid: u32) -> bool {
+    if ipi_call(hart, enable_call, call_arg(mode, eiid)).is_none() {
+        println!("Can't reach hart {}'s interrupt file to enable EIID {}.", hart, eiid);
+        return false;
+    }
+    true
+}
+
+/// # Overview
+/// Disable an EIID on any hart's interrupt file. See `imsic_enable_on`.
+/// # Returns
+/// `false` if the hart could not be reached
+pub fn imsic_disable_on(hart: usize, mode: PrivMode, eiid: u32) -> bool {
+    if ipi_call(hart, disable_call, call_arg(mode, eiid)).is_none() {
+        println!("Can't reach hart {}'s interrupt file to disable EIID {}.", hart, eiid);
+        return false;
+    }
+    true
+}
+
+/// # Overview
+/// Send a message to a hart's interrupt file by writing its MMIO page,
+/// exactly as a device would.
+/// # Arguments
+/// * `hart` - the hart to send the message to
+/// * `mode` - which of the hart's interrupt files (M or S) to write
+/// * `eiid` - the interrupt identity to send
+pub fn imsic_send(hart: usize, mode: PrivMode, eiid: u32) {
+    let addr = match mode {
+        PrivMode::Machine => imsic_m(hart),
+        PrivMode::Supervisor => imsic_s(hart),
+    };
+    unsafe {
+        // We are required to write only 32 bits.
+        write_volatile(addr as *mut u32, eiid);
+    }
+}
+
+/// # Overview
+/// Take a pending message off of an interrupt file without handling it,
+/// so that it can be sent somewhere else. Only a hart can read its own
+/// pending bits, so another hart is asked with an IPI. If it can't be
+/// reached, the EIID is assumed to be pending. Sending a message twice
+/// costs a spurious interrupt, but losing one can hang a driver.
+/// # Returns
+/// `true` if the EIID was (or might have been) pending
+pub fn imsic_take_pending(hart: usize, mode: PrivMode, eiid: u32) -> bool {
+    match ipi_call(hart, take_pending_call, call_arg(mode, eiid)) {
+        Some(pending) => pending != 0,
+        None => true,
+    }
+}
+
+fn take_pending(mode: PrivMode, which: usize) -> bool {
+    let eipbyte = EIP + XLEN_STRIDE * which / XLEN;
+    let bit = which % XLEN;
+    let reg = match mode {
+        PrivMode::Machine => {
+            imsic_write(MISELECT, eipbyte);
+            imsic_read(MIREG)
+        }
+        PrivMode::Supervisor => {
+            imsic_write(SISELECT, eipbyte);
+            imsic_read(SIREG)
+        }
+    };
+    if reg >> bit & 1 == 0 {
+        return false;
+    }
+    imsic_clear(mode, which);
+    true
+}
+
+/// # Overview
+/// Remove the handler from an EIID but keep it allocated.
+pub fn imsic_unregister(hart: usize, mode: PrivMode, eiid: u32) {
+    msi_vector(hart, mode, eiid).handler = None;
+}
+
+fn test_mmio_handler(_: usize) {
+    println!("First test triggered by MMIO write successful!");
+}
+
+fn test_eip_handler(_: usize) {
+    println!("Second test triggered by EIP successful!");
+}
+
+/// # Overview
+/// Turn on this hart's M and S interrupt files. Every hart runs this
+/// for itself, since the files are only reachable through its own CSRs.
+pub fn imsic_hart_init() {
     // First, enable the interrupt file
     // 0 = disabled
     // 1 = enabled
@@ -206,19 +490,28 @@ pub fn imsic_init() {
     // 0 = enable all interrupts
     // P = enable < P only
     // Priorities come from the interrupt number directly
+    // Since drivers allocate EIIDs anywhere in 1..=255, hear everything
+    // and let the EIE bits decide.
     imsic_write(MISELECT, EITHRESHOLD);
-    // Only hear 0, 1, 2, 3, and 4
-    imsic_write(MIREG, 5);
+    imsic_write(MIREG, 0);
 
-    // Hear message 10
     imsic_write(SISELECT, EITHRESHOLD);
-    imsic_write(SIREG, 11);
+    imsic_write(SIREG, 0);
+}
 
-    // Enable message #10. This will be UART when delegated by the
-    // APLIC.
+/// # Overview
+/// Set up the boot hart's interrupt files and send it two test messages.
+pub fn imsic_init() {
+    let hartid = csr_read!("mhartid");
+    imsic_hart_init();
+
+    // The two test messages use hard-coded EIIDs 2 and 4.
+    imsic_reserve(hartid, PrivMode::Machine, 2);
+    imsic_register(hartid, PrivMode::Machine, 2, test_mmio_handler, 0);
+    imsic_reserve(hartid, PrivMode::Machine, 4);
+    imsic_register(hartid, PrivMode::Machine, 4, test_eip_handler, 0);
     imsic_enable(PrivMode::Machine, 2);
     imsic_enable(PrivMode::Machine, 4);
-    imsic_enable(PrivMode::Supervisor, 10);
 
     // Trigger interrupt #2
     // SETEIPNUM no longer works
@@ -247,11 +540,17 @@ fn imsic_pop(pr: PrivMode) -> u32 {
 
 /// Handle an IMSIC trap. Called from `trap::rust_trap`
 pub fn imsic_handle(pm: PrivMode) {
+    let hart = csr_read!("mhartid");
     match imsic_pop(pm) {
         0 => println!("Spurious 'no' message."),
-        2 => println!("First test triggered by MMIO write successful!"),
-        4 => println!("Second test triggered by EIP successful!"),
-        10 => console_irq(),
-        msinum => println!("Unknown msi #{}", msinum),
+        // Past what we keep handlers for, so nobody can have asked for it.
+        msinum if msinum as usize >= IMSIC_NUM_IDS => println!("Unknown msi #{}", msinum),
+        msinum => {
+            let vec = msi_vector(hart, pm, msinum);
+            match vec.handler {
+                Some(handler) => handler(vec.data),
+                None => println!("Unknown msi #{}", msinum),
+            }
+        }
     }
 }
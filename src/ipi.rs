@@ -0,0 +1,286 @@
+//! ipi.rs
+//! Inter-processor interrupts sent as IMSIC messages
+//! Stephen Marz
+//! 17-Oct-2026
+
+use crate::{
+    imsic::{imsic_enable, imsic_register, imsic_reserve, imsic_send, PrivMode},
+    smp::hart_online,
+    timer::{timer_deadline, timer_expired},
+    MAX_HARTS,
+};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+// Every hart reserves this EIID on its M-mode interrupt file, and an IPI
+// is just this EIID written to the target's M-mode IMSIC page. It is the
+// lowest (most urgent) EIID there is.
+pub const IPI_EIID: u32 = 1;
+
+// How long to wait for another hart to finish a call or a shootdown
+const IPI_TIMEOUT_MS: u64 = 1000;
+
+/// Why a hart is being interrupted. Several reasons can be pending at
+/// once, and they all go out as one message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IpiReason {
+    // Look for other work. There is no scheduler yet, so this only ends
+    // a wfi.
+    Reschedule = 0,
+    // Flush the address translation caches
+    TlbShootdown = 1,
+    // Run the function in the hart's call slot
+    Call = 2,
+}
+
+/// A function run on another hart. It gets the argument it was sent
+/// with, and what it returns goes back to the caller.
+pub type IpiFunc = fn(usize) -> usize;
+
+// Where a hart's call slot is at. The slot goes FREE -> POSTED -> DONE
+// -> FREE. If the caller gives up waiting, it goes POSTED -> ABANDONED,
+// and the hart frees it when the call finally runs.
+const CALL_FREE: usize = 0;
+const CALL_POSTED: usize = 1;
+const CALL_DONE: usize = 2;
+const CALL_ABANDONED: usize = 3;
+
+/// One hart's IPI state. Other harts write to it, so it is all atomics.
+struct IpiState {
+    // A bit for each IpiReason that is waiting to be handled
+    pending: AtomicUsize,
+    // One of the CALL_ states
+    call_state: AtomicUsize,
+    call_func: AtomicUsize,
+    call_arg: AtomicUsize,
+    call_ret: AtomicUsize,
+    // Cleared by the hart asking for a shootdown and set once this hart
+    // has flushed
+    tlb_done: AtomicBool,
+    // Set by a reschedule IPI and cleared by `ipi_need_resched`
+    need_resched: AtomicBool,
+}
+
+impl IpiState {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self = Self {
+        pending: AtomicUsize::new(0),
+        call_state: AtomicUsize::new(CALL_FREE),
+        call_func: AtomicUsize::new(0),
+        call_arg: AtomicUsize::new(0),
+        call_ret: AtomicUsize::new(0),
+        tlb_done: AtomicBool::new(true),
+        need_resched: AtomicBool::new(false),
+    };
+}
+
+static IPI_STATE: [IpiState; MAX_HARTS] = [IpiState::NEW; MAX_HARTS];
+
+// Only one shootdown goes on at a time, since they share the tlb_done
+// flags.
+static TLB_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// # Overview
+/// Reserve and enable the IPI EIID on this hart and hook up the handler.
+/// Every hart runs this for itself.
+/// # Arguments
+/// * `hart` - this hart's ID
+pub fn ipi_hart_init(hart: usize) {
+    imsic_reserve(hart, PrivMode::Machine, IPI_EIID);
+    imsic_register(hart, PrivMode::Machine, IPI_EIID, ipi_handler, 0);
+    imsic_enable(PrivMode::Machine, IPI_EIID as usize);
+}
+
+/// # Overview
+/// Interrupt another hart. The reason is recorded before the message is
+/// sent, so the target always finds it.
+/// # Arguments
+/// * `hart` - the hart to interrupt
+/// * `reason` - what the hart should do
+pub fn ipi_send(hart: usize, reason: IpiReason) {
+    assert!(hart < MAX_HARTS);
+    IPI_STATE[hart].pending.fetch_or(1 << reason as usize, Ordering::SeqCst);
+    unsafe {
+        // The reason has to be visible before the message lands.
+        asm!("fence w, o");
+    }
+    imsic_send(hart, PrivMode::Machine, IPI_EIID);
+}
+
+/// # Overview
+/// Interrupt every online hart but this one.
+pub fn ipi_broadcast(reason: IpiReason) {
+    let me = csr_read!("mhartid");
+    for hart in (0..MAX_HARTS).filter(|&h| h != me && hart_online(h)) {
+        ipi_send(hart, reason);
+    }
+}
+
+/// # Overview
+/// Run a function on another hart and wait for it to finish. If the hart
+/// is this one, the function is just called. Don't call this with
+/// interrupts off from a hart that the target might be calling at the
+/// same time, since neither would ever take the other's call.
+/// # Arguments
+/// * `hart` - the hart to run `func` on
+/// * `func` - the function to run
+/// * `arg` - the argument to give `func`
+/// # Returns
+/// What `func` returned, or `None` if the hart is not online, its call
+/// slot stayed busy, or it did not finish in time
+pub fn ipi_call(hart: usize, func: IpiFunc, arg: usize) -> Option<usize> {
+    if hart == csr_read!("mhartid") {
+        return Some(func(arg));
+    }
+    if !hart_online(hart) {
+        return None;
+    }
+    let state = &IPI_STATE[hart];
+    // Take the hart's call slot. Whoever is done with it last gives it
+    // back: the caller once it has the return value, or the hart itself
+    // if the caller gave up.
+    let taken = wait_for(|| {
+        state
+            .call_state
+            .compare_exchange(CALL_FREE, CALL_POSTED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    });
+    if !taken {
+        println!("Hart {}'s call slot is still busy.", hart);
+        return None;
+    }
+    state.call_func.store(func as usize, Ordering::Relaxed);
+    state.call_arg.store(arg, Ordering::Relaxed);
+    ipi_send(hart, IpiReason::Call);
+    let done = wait_for(|| state.call_state.load(Ordering::Acquire) == CALL_DONE)
+        || state
+            .call_state
+            .compare_exchange(CALL_POSTED, CALL_ABANDONED, Ordering::AcqRel, Ordering::Acquire)
+            .is_err();
+    if !done {
+        println!("Call on hart {} timed out.", hart);
+        return None;
+    }
+    // The call finished, even if it was just as we gave up.
+    let ret = state.call_ret.load(Ordering::Relaxed);
+    state.call_state.store(CALL_FREE, Ordering::Release);
+    Some(ret)
+}
+
+/// # Overview
+/// Flush the address translation caches on every online hart, this one
+/// included, and wait for all of them to finish.
+/// # Returns
+/// `false` if a hart did not finish in time
+pub fn ipi_tlb_shootdown() -> bool {
+    while TLB_LOCK
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    let me = csr_read!("mhartid");
+    let others = (0..MAX_HARTS).filter(|&h| h != me && hart_online(h));
+    for hart in others.clone() {
+        IPI_STATE[hart].tlb_done.store(false, Ordering::Release);
+        ipi_send(hart, IpiReason::TlbShootdown);
+    }
+    tlb_flush();
+    let mut ok = true;
+    for hart in others {
+        let state = &IPI_STATE[hart];
+        if !wait_for(|| state.tlb_done.load(Ordering::Acquire)) {
+            println!("Hart {} did not finish its TLB shootdown.", hart);
+            ok = false;
+        }
+    }
+    TLB_LOCK.store(false, Ordering::Release);
+    ok
+}
+
+/// # Overview
+/// See whether a reschedule IPI came in since the last time we looked.
+pub fn ipi_need_resched() -> bool {
+    let me = csr_read!("mhartid");
+    IPI_STATE[me].need_resched.swap(false, Ordering::AcqRel)
+}
+
+/// # Overview
+/// Wait for another hart to do something.
+/// # Returns
+/// `false` if `done` didn't return `true` within IPI_TIMEOUT_MS
+fn wait_for(done: impl Fn() -> bool) -> bool {
+    let deadline = timer_deadline(IPI_TIMEOUT_MS);
+    loop {
+        // Look at the clock first, so `done` gets one last look after
+        // the deadline passes.
+        let expired = timer_expired(deadline);
+        if done() {
+            return true;
+        }
+        if expired {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn tlb_flush() {
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
+
+/// # Overview
+/// Handle every IPI reason pending on this hart. Called from the IMSIC
+/// when IPI_EIID is popped.
+fn ipi_handler(_: usize) {
+    let me = csr_read!("mhartid");
+    let state = &IPI_STATE[me];
+    let pending = state.pending.swap(0, Ordering::AcqRel);
+    if pending & 1 << IpiReason::Reschedule as usize != 0 {
+        state.need_resched.store(true, Ordering::Release);
+    }
+    if pending & 1 << IpiReason::TlbShootdown as usize != 0 {
+        tlb_flush();
+        state.tlb_done.store(true, Ordering::Release);
+    }
+    if pending & 1 << IpiReason::Call as usize != 0 {
+        let func = state.call_func.load(Ordering::Relaxed);
+        let arg = state.call_arg.load(Ordering::Relaxed);
+        // Only ipi_call stores here, and it always stores an IpiFunc.
+        let func: IpiFunc = unsafe { core::mem::transmute(func) };
+        state.call_ret.store(func(arg), Ordering::Relaxed);
+        // If the caller gave up on us, nobody is left to free the slot.
+        if state
+            .call_state
+            .compare_exchange(CALL_POSTED, CALL_DONE, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            state.call_state.store(CALL_FREE, Ordering::Release);
+        }
+    }
+}
+
+fn ipi_test_call(arg: usize) -> usize {
+    println!("Hart {} got call with argument {}.", csr_read!("mhartid"), arg);
+    arg + 1
+}
+
+/// # Overview
+/// Call a function on every other online hart and do a TLB shootdown.
+/// This is the `ipi` console command.
+pub fn ipi_test() {
+    let me = csr_read!("mhartid");
+    for hart in (0..MAX_HARTS).filter(|&h| h != me && hart_online(h)) {
+        match ipi_call(hart, ipi_test_call, hart * 100) {
+            Some(ret) => println!("Call on hart {} returned {}.", hart, ret),
+            None => println!("Call on hart {} failed.", hart),
+        }
+    }
+    if ipi_tlb_shootdown() {
+        println!("TLB shootdown done.");
+    }
+}
@@ -0,0 +1,218 @@
+//! irq.rs
+//! Interrupt controller backends
+//! Stephen Marz
+//! 17-Oct-2026
+
+use crate::imsic::PrivMode;
+
+// The backend is picked at build time with one of these cargo features:
+//   aia-msi    - APLIC sending MSIs to the IMSICs (aia=aplic-imsic)
+//   aia-direct - APLIC interrupting the harts through its IDCs (aia=aplic)
+//   plic       - SiFive PLIC (aia=none)
+#[cfg(not(any(feature = "aia-msi", feature = "aia-direct", feature = "plic")))]
+compile_error!("Pick an interrupt backend: aia-msi, aia-direct or plic.");
+#[cfg(any(
+    all(feature = "aia-msi", feature = "aia-direct"),
+    all(feature = "aia-msi", feature = "plic"),
+    all(feature = "aia-direct", feature = "plic"),
+))]
+compile_error!("Only one interrupt backend can be picked. Use --no-default-features.");
+
+/// A handler for a wired interrupt source. The argument is the `data`
+/// value given when the handler was registered.
+pub type IrqHandler = fn(usize);
+
+/// What the rest of the kernel needs from an interrupt controller. Wired
+/// sources keep their numbers from virt.c no matter which backend
+/// delivers them.
+pub trait InterruptController: Sync {
+    fn name(&self) -> &'static str;
+
+    /// # Overview
+    /// Set the controller up on the boot hart.
+    fn init(&self);
+
+    /// # Overview
+    /// See whether PCI devices can send MSIs.
+    /// # Returns
+    /// `true` if there are IMSICs to receive them
+    fn has_msi(&self) -> bool;
+
+    /// # Overview
+    /// Send a wired source to a hart's S mode and call a handler when it
+    /// fires. The source is left disabled.
+    /// # Returns
+    /// `false` if the source already has a handler
+    fn request(&self, irq: u32, hart: usize, handler: IrqHandler, data: usize) -> bool;
+
+    /// # Overview
+    /// Enable or disable a source added with `request`.
+    fn set_enabled(&self, irq: u32, enabled: bool);
+
+    /// # Overview
+    /// Move a source added with `request` to another hart without losing
+    /// an interrupt.
+    /// # Returns
+    /// `false` if the source could not be moved
+    fn set_affinity(&self, irq: u32, hart: usize) -> bool;
+
+    /// # Overview
+    /// Handle an external interrupt.
+    /// # Arguments
+    /// * `mode` - machine (cause 11) or supervisor (cause 9)
+    fn handle(&self, mode: PrivMode);
+}
+
+#[cfg(feature = "aia-msi")]
+mod backend {
+    use super::{InterruptController, IrqHandler};
+    use crate::{
+        aplic::{aplic_init, aplic_request, aplic_set_affinity, aplic_set_enabled},
+        imsic::{imsic_handle, imsic_init, PrivMode},
+    };
+
+    pub struct AiaMsi;
+
+    impl InterruptController for AiaMsi {
+        fn name(&self) -> &'static str {
+            "APLIC + IMSIC (MSI)"
+        }
+
+        fn init(&self) {
+            imsic_init();
+            aplic_init(false);
+        }
+
+        fn has_msi(&self) -> bool {
+            true
+        }
+
+        fn request(&self, irq: u32, hart: usize, handler: IrqHandler, data: usize) -> bool {
+            aplic_request(irq, hart, handler, data)
+        }
+
+        fn set_enabled(&self, irq: u32, enabled: bool) {
+            aplic_set_enabled(irq, enabled);
+        }
+
+        fn set_affinity(&self, irq: u32, hart: usize) -> bool {
+            aplic_set_affinity(irq, hart)
+        }
+
+        fn handle(&self, mode: PrivMode) {
+            imsic_handle(mode);
+        }
+    }
+
+    pub static CONTROLLER: AiaMsi = AiaMsi;
+}
+
+#[cfg(feature = "aia-direct")]
+mod backend {
+    use super::{InterruptController, IrqHandler};
+    use crate::{
+        aplic::{
+            aplic_handle, aplic_init, aplic_request, aplic_set_affinity, aplic_set_enabled,
+        },
+        imsic::PrivMode,
+    };
+
+    pub struct AiaDirect;
+
+    impl InterruptController for AiaDirect {
+        fn name(&self) -> &'static str {
+            "APLIC (direct)"
+        }
+
+        fn init(&self) {
+            aplic_init(true);
+        }
+
+        fn has_msi(&self) -> bool {
+            false
+        }
+
+        fn request(&self, irq: u32, hart: usize, handler: IrqHandler, data: usize) -> bool {
+            aplic_request(irq, hart, handler, data)
+        }
+
+        fn set_enabled(&self, irq: u32, enabled: bool) {
+            aplic_set_enabled(irq, enabled);
+        }
+
+        fn set_affinity(&self, irq: u32, hart: usize) -> bool {
+            aplic_set_affinity(irq, hart)
+        }
+
+        fn handle(&self, mode: PrivMode) {
+            // Everything is delegated to the S domain.
+            match mode {
+                PrivMode::Supervisor => aplic_handle(),
+                PrivMode::Machine => println!("Unexpected M-mode external interrupt."),
+            }
+        }
+    }
+
+    pub static CONTROLLER: AiaDirect = AiaDirect;
+}
+
+#[cfg(feature = "plic")]
+mod backend {
+    use super::{InterruptController, IrqHandler};
+    use crate::{
+        imsic::PrivMode,
+        plic::{plic_handle, plic_init, plic_request, plic_set_affinity, plic_set_enabled},
+    };
+
+    pub struct Plic;
+
+    impl InterruptController for Plic {
+        fn name(&self) -> &'static str {
+            "PLIC"
+        }
+
+        fn init(&self) {
+            plic_init(0);
+        }
+
+        fn has_msi(&self) -> bool {
+            false
+        }
+
+        fn request(&self, irq: u32, hart: usize, handler: IrqHandler, data: usize) -> bool {
+            plic_request(irq, hart, handler, data)
+        }
+
+        fn set_enabled(&self, irq: u32, enabled: bool) {
+            plic_set_enabled(irq, enabled);
+        }
+
+        fn set_affinity(&self, irq: u32, hart: usize) -> bool {
+            plic_set_affinity(irq, hart)
+        }
+
+        fn handle(&self, mode: PrivMode) {
+            // We only use the S-mode contexts.
+            match mode {
+                PrivMode::Supervisor => plic_handle(),
+                PrivMode::Machine => println!("Unexpected M-mode external interrupt."),
+            }
+        }
+    }
+
+    pub static CONTROLLER: Plic = Plic;
+}
+
+/// # Overview
+/// Get the interrupt controller this kernel was built for.
+pub fn controller() -> &'static dyn InterruptController {
+    &backend::CONTROLLER
+}
+
+/// # Overview
+/// Set up the interrupt controller on the boot hart.
+pub fn irq_init() {
+    let ctrl = controller();
+    println!("Interrupt controller: {}.", ctrl.name());
+    ctrl.init();
+}
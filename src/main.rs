@@ -5,7 +5,7 @@ use core::arch::{asm, global_asm};
 
 // Include both assembly files and parse them as
 // assembly.
-global_asm!(include_str!("start.S"));
+global_asm!(include_str!("start.S"), max_harts = const MAX_HARTS);
 global_asm!(include_str!("trap.S"));
 
 #[macro_export]
@@ -67,7 +67,8 @@ macro_rules! csr_read {
 
 // MAX_HARTS determines how many harts can run on this OS. If a HART is not permitted to
 // run, it will be sent to park and never be able to leave, hence turning it off.
-const MAX_HARTS: usize = 1;
+// The linker script gives each of them an 8K stack, so keep it in step.
+pub const MAX_HARTS: usize = 4;
 // Trap frames are used to store the 32 general purpose registers when a hart enters a
 // trap.
 static mut TRAP_FRAMES: [[usize; 32]; MAX_HARTS] = [[0; 32]; MAX_HARTS];
@@ -87,20 +88,33 @@ fn main(hart: usize) {
         console::uart_init();
         // Setup the IMSIC and see what happens!
         println!("Booted on hart {}.", hart);
-        imsic::imsic_init();
-        aplic::aplic_init();
+        irq::irq_init();
+        timer::timer_hart_init();
+        console::uart_irq_init();
         page::page_init();
+        smp::smp_boot();
         let v: Option<i32> = None;
         v.expect("John");
         console::run();
+    } else {
+        smp::smp_secondary(hart);
     }
 }
 
+#[cfg(any(feature = "aia-msi", feature = "aia-direct"))]
 pub mod aplic;
 pub mod console;
 pub mod imsic;
+pub mod ipi;
+pub mod irq;
 pub mod nvme;
 pub mod page;
 pub mod pci;
+pub mod pcieport;
+#[cfg(feature = "plic")]
+pub mod plic;
 pub mod ringbuffer;
+pub mod smp;
+pub mod timer;
 pub mod trap;
+pub mod volatile;
@@ -3,10 +3,929 @@
 //! Stephen Marz
 //! 20-Sep-2022
 
-use crate::pci::{PCI_INITIALIZED, PCI_DEVICES, PciDevice};
+#![allow(dead_code)]
+
+use crate::{
+    imsic::{imsic_disable_on, imsic_enable, imsic_free, imsic_register, PrivMode},
+    page::{align_down, alloc_page, PAGE_SIZE},
+    pci::{
+        pci_register_driver, MsiVectors, PciDevice, PciDeviceId, PciDriver, PCI_INITIALIZED,
+    },
+    smp::hart_online,
+    timer::{timer_deadline, timer_expired},
+    volatile::Volatile,
+    MAX_HARTS,
+};
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_bytes, write_volatile};
 
 static mut NVME_INITIALIZED: bool = false;
 
+// The number of entries we give the admin queues. One page of
+// 64-byte submission entries is 64 entries, and we cap the
+// completion queue at the same number so they stay in lock step.
+const ADMIN_QUEUE_SIZE: usize = PAGE_SIZE / 64;
+
+// Doorbells start at offset 0x1000 from the controller's BAR. Each
+// queue has a submission tail and completion head doorbell,
+// separated by (4 << CAP.DSTRD) bytes.
+const DOORBELL_OFFSET: usize = 0x1000;
+
+// CAP.TO is in units of 500 milliseconds.
+const TIMEOUT_UNIT_MS: u64 = 500;
+
+// Controller configuration (CC) bits
+const CC_EN: u32 = 1 << 0;
+// I/O submission queue entry size 2^6 = 64 bytes
+const CC_IOSQES: u32 = 6 << 16;
+// I/O completion queue entry size 2^4 = 16 bytes
+const CC_IOCQES: u32 = 4 << 20;
+
+// Controller status (CSTS) bits
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+// Admin command opcodes
+const ADMIN_CREATE_IO_SQ: u32 = 0x01;
+const ADMIN_CREATE_IO_CQ: u32 = 0x05;
+const ADMIN_IDENTIFY: u32 = 0x06;
+const ADMIN_SET_FEATURES: u32 = 0x09;
+
+// Feature identifiers for Set Features
+const FEATURE_NUM_QUEUES: u32 = 0x07;
+
+// NVM command set opcodes
+const NVM_WRITE: u32 = 0x01;
+const NVM_READ: u32 = 0x02;
+
+// The number of I/O queue pairs we ask for. The controller might
+// give us fewer.
+const IO_QUEUES: usize = 2;
+const IO_QUEUE_SIZE: usize = PAGE_SIZE / 64;
+
+// Completions are filed by command identifier into this many slots.
+// No queue is bigger than this, so a full queue can't collide.
+const QUEUE_SLOTS: usize = PAGE_SIZE / 64;
+
+// A PRP list is one page of 64-bit page addresses. We don't chain
+// lists, so a single command can cover at most this many pages.
+const PRP_ENTRIES_PER_PAGE: usize = PAGE_SIZE / 8;
+
+// Identify CNS values (CDW10)
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+const CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+
+// The Active Namespace ID list is a page of NSIDs, ended early by a 0.
+const NSID_LIST_ENTRIES: usize = PAGE_SIZE / 4;
+
+// The number of namespaces per controller we keep identify data for.
+const MAX_NAMESPACES: usize = 4;
+
+/// The controller registers at the top of BAR 0.
+#[repr(C)]
+struct NvmeRegs {
+    pub cap: Volatile<u64>,
+    pub vs: Volatile<u32>,
+    pub intms: Volatile<u32>,
+    pub intmc: Volatile<u32>,
+    pub cc: Volatile<u32>,
+    _reserved0: u32,
+    pub csts: Volatile<u32>,
+    pub nssr: Volatile<u32>,
+    pub aqa: Volatile<u32>,
+    pub asq: Volatile<u64>,
+    pub acq: Volatile<u64>,
+}
+
+impl NvmeRegs {
+    pub fn as_ref<'a>(base: usize) -> &'a Self {
+        unsafe { (base as *const Self).as_ref().unwrap() }
+    }
+}
+
+/// # Overview
+/// Get the doorbell register at the given MMIO address.
+fn doorbell(addr: usize) -> &'static Volatile<u32> {
+    unsafe { (addr as *const Volatile<u32>).as_ref().unwrap() }
+}
+
+/// A 64-byte submission queue entry (command).
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SubmissionEntry {
+    pub cdw0: u32,
+    pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub mptr: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+/// A 16-byte completion queue entry.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct CompletionEntry {
+    pub dw0: u32,
+    pub dw1: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub cid: u16,
+    pub status: u16,
+}
+
+impl CompletionEntry {
+    /// The phase tag is bit 0 of the status field.
+    pub fn phase(&self) -> bool {
+        self.status & 1 == 1
+    }
+
+    /// The status code type and status code (bits 11:1). 0 is success.
+    pub fn status_code(&self) -> u16 {
+        self.status >> 1 & 0x7FF
+    }
+}
+
+/// One LBA format descriptor from Identify Namespace.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct LbaFormat {
+    // Metadata size in bytes
+    pub ms: u16,
+    // LBA data size as a power of two
+    pub lbads: u8,
+    // Relative performance (0 = best)
+    pub rp: u8,
+}
+
+/// The 4 KiB Identify Controller data structure (CNS 0x01).
+/// Only the fields we use are broken out.
+#[repr(C)]
+pub struct IdentifyController {
+    pub vid: u16,
+    pub ssvid: u16,
+    pub sn: [u8; 20],
+    pub mn: [u8; 40],
+    pub fr: [u8; 8],
+    pub rab: u8,
+    pub ieee: [u8; 3],
+    pub cmic: u8,
+    pub mdts: u8,
+    pub cntlid: u16,
+    pub ver: u32,
+    _reserved0: [u8; 428],
+    pub sqes: u8,
+    pub cqes: u8,
+    pub maxcmd: u16,
+    pub nn: u32,
+    _reserved1: [u8; 248],
+    pub subnqn: [u8; 256],
+    _reserved2: [u8; 3072],
+}
+
+/// The 4 KiB Identify Namespace data structure (CNS 0x00).
+#[repr(C)]
+pub struct IdentifyNamespace {
+    pub nsze: u64,
+    pub ncap: u64,
+    pub nuse: u64,
+    pub nsfeat: u8,
+    pub nlbaf: u8,
+    pub flbas: u8,
+    pub mc: u8,
+    pub dpc: u8,
+    pub dps: u8,
+    pub nmic: u8,
+    pub rescap: u8,
+    _reserved0: [u8; 96],
+    pub lbaf: [LbaFormat; 64],
+    _reserved1: [u8; 3712],
+}
+
+/// Identify strings are space padded ASCII, not NUL terminated.
+fn ident_str(bytes: &[u8]) -> &str {
+    let end = bytes.iter().rposition(|&b| b != b' ' && b != 0).map_or(0, |i| i + 1);
+    core::str::from_utf8(&bytes[..end]).unwrap_or("?")
+}
+
+impl IdentifyController {
+    pub fn serial(&self) -> &str {
+        ident_str(&self.sn)
+    }
+
+    pub fn model(&self) -> &str {
+        ident_str(&self.mn)
+    }
+
+    pub fn firmware(&self) -> &str {
+        ident_str(&self.fr)
+    }
+
+    pub fn nqn(&self) -> &str {
+        ident_str(&self.subnqn)
+    }
+
+    /// # Overview
+    /// The maximum data transfer size in bytes.
+    /// # Returns
+    /// `None` if there is no limit, otherwise the number of bytes. MDTS
+    /// is a power of two in units of the minimum page size, which we
+    /// require to be 4 KiB.
+    pub fn max_transfer(&self) -> Option<usize> {
+        match self.mdts {
+            0 => None,
+            mdts => Some(PAGE_SIZE << mdts),
+        }
+    }
+}
+
+impl IdentifyNamespace {
+    /// The LBA format this namespace is formatted with.
+    pub fn format(&self) -> &LbaFormat {
+        &self.lbaf[(self.flbas & 0xF) as usize]
+    }
+
+    /// The size of one logical block in bytes.
+    pub fn block_size(&self) -> usize {
+        1 << self.format().lbads
+    }
+
+    /// The total number of bytes in this namespace.
+    pub fn size(&self) -> u64 {
+        self.nsze * self.block_size() as u64
+    }
+}
+
+/// A submission/completion queue pair and its doorbells.
+struct QueuePair {
+    qid: usize,
+    sq: *mut SubmissionEntry,
+    cq: *mut CompletionEntry,
+    size: usize,
+    sq_tail: usize,
+    // The last submission queue head the controller reported to us.
+    sq_head: usize,
+    cq_head: usize,
+    // The phase tag the controller will write for new completions.
+    // This flips every time the completion queue wraps.
+    phase: bool,
+    sq_doorbell: &'static Volatile<u32>,
+    cq_doorbell: &'static Volatile<u32>,
+    next_cid: u16,
+    // One page used as the PRP list for transfers larger than two
+    // pages. Only I/O queues have one.
+    prp_list: *mut u64,
+    // Completions that have been reaped but not yet picked up by
+    // whoever submitted the command.
+    done: [Option<CompletionEntry>; QUEUE_SLOTS],
+    // The MSI or MSI-X vector this completion queue interrupts on. If
+    // this is None, the queue is polled instead.
+    vector: Option<usize>,
+}
+
+impl QueuePair {
+    /// # Overview
+    /// Allocate and clear the memory for a queue pair.
+    /// # Arguments
+    /// * `base` - the controller's BAR address
+    /// * `dstrd` - the doorbell stride in bytes
+    /// * `qid` - the queue identifier (0 is the admin queue)
+    /// * `size` - the number of entries in each queue
+    /// # Returns
+    /// `None` if there is not enough memory for the queues
+    fn new(base: usize, dstrd: usize, qid: usize, size: usize) -> Option<Self> {
+        assert!(size <= QUEUE_SLOTS);
+        let sq_pages = (size * 64).div_ceil(PAGE_SIZE);
+        let cq_pages = (size * 16).div_ceil(PAGE_SIZE);
+        let sq = alloc_page(sq_pages)?;
+        let cq = alloc_page(cq_pages)?;
+        unsafe {
+            write_bytes(sq, 0, sq_pages * PAGE_SIZE);
+            write_bytes(cq, 0, cq_pages * PAGE_SIZE);
+        }
+        Some(Self {
+            qid,
+            sq: sq as *mut SubmissionEntry,
+            cq: cq as *mut CompletionEntry,
+            size,
+            sq_tail: 0,
+            sq_head: 0,
+            cq_head: 0,
+            phase: true,
+            sq_doorbell: doorbell(base + DOORBELL_OFFSET + (2 * qid) * dstrd),
+            cq_doorbell: doorbell(base + DOORBELL_OFFSET + (2 * qid + 1) * dstrd),
+            next_cid: 0,
+            prp_list: core::ptr::null_mut(),
+            done: [None; QUEUE_SLOTS],
+            vector: None,
+        })
+    }
+
+    /// # Overview
+    /// Put a command on the submission queue and ring its doorbell.
+    /// The command identifier is filled in for you.
+    /// # Returns
+    /// `Some(cid)` with the command identifier, or `None` if the queue is full.
+    fn submit(&mut self, mut cmd: SubmissionEntry) -> Option<u16> {
+        let next_tail = (self.sq_tail + 1) % self.size;
+        if next_tail == self.sq_head {
+            return None;
+        }
+        let cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        self.done[cid as usize % QUEUE_SLOTS] = None;
+        cmd.cdw0 = (cmd.cdw0 & 0xFFFF) | (cid as u32) << 16;
+        unsafe {
+            write_volatile(self.sq.add(self.sq_tail), cmd);
+        }
+        self.sq_tail = next_tail;
+        self.sq_doorbell.write(self.sq_tail as u32);
+        Some(cid)
+    }
+
+    /// # Overview
+    /// Take every new completion off of the completion queue, file it
+    /// by command identifier, and then tell the controller we consumed
+    /// them by ringing the head doorbell once.
+    /// # Returns
+    /// The number of completions reaped.
+    fn reap(&mut self) -> usize {
+        let mut reaped = 0;
+        loop {
+            let entry = unsafe { read_volatile(self.cq.add(self.cq_head)) };
+            if entry.phase() != self.phase {
+                break;
+            }
+            self.sq_head = entry.sq_head as usize;
+            unsafe {
+                write_volatile(&mut self.done[entry.cid as usize % QUEUE_SLOTS], Some(entry));
+            }
+            self.cq_head += 1;
+            if self.cq_head == self.size {
+                self.cq_head = 0;
+                self.phase = !self.phase;
+            }
+            reaped += 1;
+        }
+        if reaped > 0 {
+            self.cq_doorbell.write(self.cq_head as u32);
+        }
+        reaped
+    }
+
+    /// # Overview
+    /// Submit a command and wait until its completion shows up. If the
+    /// queue has an interrupt, `nvme_vector_handler` reaps the completion for
+    /// us, otherwise we poll the completion queue ourselves.
+    /// # Arguments
+    /// * `cmd` - the command to run
+    /// * `timeout` - how long to wait in units of 500 ms
+    /// # Returns
+    /// The completion entry if the command succeeded, `None` if it timed
+    /// out or returned an error status.
+    fn execute(&mut self, cmd: SubmissionEntry, timeout: usize) -> Option<CompletionEntry> {
+        let opcode = cmd.cdw0 & 0xFF;
+        let cid = self.submit(cmd)?;
+        let slot = cid as usize % QUEUE_SLOTS;
+        let deadline = timer_deadline(timeout.max(1) as u64 * TIMEOUT_UNIT_MS);
+        loop {
+            // Look at the clock first, so the queue gets one last look
+            // after the deadline passes.
+            let expired = timer_expired(deadline);
+            if self.vector.is_none() {
+                self.reap();
+            }
+            if let Some(entry) = unsafe { read_volatile(&self.done[slot]) } {
+                self.done[slot] = None;
+                if entry.status_code() != 0 {
+                    println!(
+                        "NVMe command 0x{:02x} on queue {} failed with status 0x{:03x}.",
+                        opcode,
+                        self.qid,
+                        entry.status_code()
+                    );
+                    return None;
+                }
+                return Some(entry);
+            }
+            if expired {
+                break;
+            }
+        }
+        println!("NVMe command 0x{:02x} on queue {} timed out.", opcode, self.qid);
+        None
+    }
+}
+
+/// One NVMe controller.
+pub struct Nvme {
+    // Where this controller is in NVME_DEVICES
+    index: usize,
+    base: usize,
+    msi: Option<MsiVectors>,
+    // The EIID each vector sends, how many vectors we set up and the
+    // hart whose interrupt file each one goes to
+    eiids: [u32; 1 + IO_QUEUES],
+    vectors: usize,
+    harts: [usize; 1 + IO_QUEUES],
+    // Doorbell stride in bytes
+    dstrd: usize,
+    // Maximum queue entries supported (already converted from 0's based)
+    mqes: usize,
+    // Worst case time to wait for CSTS.RDY in units of 500 ms
+    timeout: usize,
+    admin: QueuePair,
+    ctrl: Option<&'static IdentifyController>,
+    namespaces: [Option<(u32, &'static IdentifyNamespace)>; MAX_NAMESPACES],
+    io: [Option<QueuePair>; IO_QUEUES],
+    // The number of I/O queues we actually created
+    io_queues: usize,
+    // The I/O queue the next command goes to. We just round robin.
+    next_io: usize,
+}
+
+impl Nvme {
+    /// # Overview
+    /// Get a queue pair by its queue identifier.
+    fn queue(&mut self, qid: usize) -> Option<&mut QueuePair> {
+        match qid {
+            0 => Some(&mut self.admin),
+            qid => self.io.get_mut(qid - 1)?.as_mut(),
+        }
+    }
+
+    /// # Overview
+    /// Allocate a vector per completion queue (the admin queue and each
+    /// I/O queue), each sending an EIID on this hart's M-mode interrupt
+    /// file. This works with either MSI or MSI-X. If the controller gives
+    /// us fewer vectors than queues, queues share them.
+    fn setup_vectors(&mut self) {
+        let mut msi = match self.msi {
+            Some(msi) => msi,
+            None => return,
+        };
+        let hart = csr_read!("mhartid");
+        self.harts = [hart; 1 + IO_QUEUES];
+        self.vectors = msi.alloc(hart, PrivMode::Machine, &mut self.eiids);
+        for (vector, &eiid) in self.eiids.iter().enumerate().take(self.vectors) {
+            let data = self.index << 16 | vector;
+            imsic_register(hart, PrivMode::Machine, eiid, nvme_vector_handler, data);
+            imsic_enable(PrivMode::Machine, eiid as usize);
+            msi.unmask(vector);
+            println!("NVMe vector {} -> EIID {}.", vector, eiid);
+        }
+    }
+
+    /// # Overview
+    /// Move one of the controller's vectors, and so the completion
+    /// queues that interrupt on it, to another hart.
+    /// # Arguments
+    /// * `vector` - the vector number
+    /// * `hart` - the hart to send it to
+    /// # Returns
+    /// `false` if there is no such vector or it could not be moved
+    fn set_affinity(&mut self, vector: usize, hart: usize) -> bool {
+        let msi = match self.msi.as_mut() {
+            Some(msi) if vector < self.vectors => msi,
+            _ => return false,
+        };
+        let from = self.harts[vector];
+        if from == hart {
+            return true;
+        }
+        match msi.migrate(vector, from, hart, PrivMode::Machine, self.eiids[vector]) {
+            Some(eiid) => {
+                println!("NVMe vector {} -> hart {} EIID {}.", vector, hart, eiid);
+                self.eiids[vector] = eiid;
+                self.harts[vector] = hart;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// # Overview
+    /// Hand the I/O vectors out to the harts that are up, one each in
+    /// turn, so that I/O completions aren't all taken on this hart.
+    /// Vector 0 stays here with the admin queue.
+    fn spread_vectors(&mut self) {
+        let harts = (0..MAX_HARTS).filter(|&h| hart_online(h)).cycle().skip(1);
+        for (vector, hart) in (1..self.vectors).zip(harts) {
+            if !self.set_affinity(vector, hart) {
+                println!("Unable to move NVMe vector {} to hart {}.", vector, hart);
+            }
+        }
+    }
+
+    /// # Overview
+    /// The vector a completion queue should interrupt on.
+    /// # Returns
+    /// `None` if we have no vectors, so the queue has to be polled.
+    fn queue_vector(&self, qid: usize) -> Option<usize> {
+        match self.vectors {
+            0 => None,
+            vectors => Some(qid % vectors),
+        }
+    }
+
+    /// # Overview
+    /// Submit an admin command and wait for it to complete.
+    /// # Returns
+    /// The completion entry if the command succeeded, `None` if it timed
+    /// out or returned an error status.
+    fn admin_command(&mut self, cmd: SubmissionEntry) -> Option<CompletionEntry> {
+        self.admin.execute(cmd, self.timeout)
+    }
+
+    /// # Overview
+    /// Issue an Identify command.
+    /// # Arguments
+    /// * `cns` - which structure to return
+    /// * `nsid` - the namespace, if the structure is namespace specific
+    /// * `page` - the page to put the data structure in
+    /// # Returns
+    /// `true` if the command succeeded
+    fn identify(&mut self, cns: u32, nsid: u32, page: *mut u8) -> bool {
+        let cmd = SubmissionEntry {
+            cdw0: ADMIN_IDENTIFY,
+            nsid,
+            prp1: page as usize as u64,
+            cdw10: cns,
+            ..Default::default()
+        };
+        self.admin_command(cmd).is_some()
+    }
+
+    /// Read the Identify Controller structure into a new page.
+    fn identify_controller(&mut self) -> Option<&'static IdentifyController> {
+        let page = alloc_page(1)?;
+        if !self.identify(CNS_CONTROLLER, 0, page) {
+            return None;
+        }
+        unsafe { (page as *const IdentifyController).as_ref() }
+    }
+
+    /// Read the Identify Namespace structure for `nsid` into `page`.
+    fn identify_namespace(
+        &mut self,
+        nsid: u32,
+        page: *mut u8,
+    ) -> Option<&'static IdentifyNamespace> {
+        if !self.identify(CNS_NAMESPACE, nsid, page) {
+            return None;
+        }
+        unsafe { (page as *const IdentifyNamespace).as_ref() }
+    }
+
+    /// # Overview
+    /// Read the controller's identify data and the identify data of
+    /// each active namespace. The controller lists the active NSIDs for
+    /// us, so we don't have to ask about every NSID up to NN. The page
+    /// allocator can't free, so a page is only given up to a namespace
+    /// we keep.
+    fn identify_all(&mut self) -> bool {
+        let ctrl = match self.identify_controller() {
+            Some(ctrl) => ctrl,
+            None => return false,
+        };
+        self.ctrl = Some(ctrl);
+        let list = match alloc_page(1) {
+            Some(list) => list,
+            None => return false,
+        };
+        // NSIDs greater than 0, so all of them
+        if !self.identify(CNS_ACTIVE_NAMESPACES, 0, list) {
+            println!("Unable to list NVMe namespaces.");
+            return true;
+        }
+        let list = unsafe { core::slice::from_raw_parts(list as *const u32, NSID_LIST_ENTRIES) };
+        let mut page = None;
+        let mut slot = 0;
+        for &nsid in list.iter().take_while(|&&nsid| nsid != 0) {
+            if slot >= MAX_NAMESPACES {
+                println!("NVMe only tracking the first {} namespaces.", MAX_NAMESPACES);
+                break;
+            }
+            // Reuse the last page if its namespace didn't identify.
+            let buf = match page.or_else(|| alloc_page(1)) {
+                Some(buf) => buf,
+                None => break,
+            };
+            page = Some(buf);
+            if let Some(ns) = self.identify_namespace(nsid, buf) {
+                self.namespaces[slot] = Some((nsid, ns));
+                slot += 1;
+                page = None;
+            }
+        }
+        true
+    }
+
+    /// # Overview
+    /// Find the identify data for a namespace.
+    pub fn namespace(&self, nsid: u32) -> Option<&'static IdentifyNamespace> {
+        self.namespaces.iter().flatten().find(|(id, _)| *id == nsid).map(|(_, ns)| *ns)
+    }
+
+    /// # Overview
+    /// Ask for I/O queues with Set Features, then create each
+    /// completion queue followed by its submission queue.
+    /// # Returns
+    /// The number of I/O queue pairs created, `None` if none could be.
+    fn create_io_queues(&mut self) -> Option<usize> {
+        // Number of Queues is 0's based for both submission and completion.
+        let want = (IO_QUEUES - 1) as u32;
+        let cmd = SubmissionEntry {
+            cdw0: ADMIN_SET_FEATURES,
+            cdw10: FEATURE_NUM_QUEUES,
+            cdw11: want << 16 | want,
+            ..Default::default()
+        };
+        let entry = self.admin_command(cmd)?;
+        let nsq = (entry.dw0 & 0xFFFF) as usize + 1;
+        let ncq = (entry.dw0 >> 16) as usize + 1;
+        let count = IO_QUEUES.min(nsq).min(ncq);
+        let size = IO_QUEUE_SIZE.min(self.mqes);
+
+        for i in 0..count {
+            let qid = i + 1;
+            let mut queue = QueuePair::new(self.base, self.dstrd, qid, size)?;
+            queue.prp_list = alloc_page(1)? as *mut u64;
+            // CDW11 bit 0 is physically contiguous, which our pages are.
+            // Bit 1 enables interrupts, and bits 31:16 are the MSI-X vector.
+            let vector = self.queue_vector(qid);
+            let irq = match vector {
+                Some(vector) => (vector as u32) << 16 | 1 << 1,
+                None => 0,
+            };
+            let cmd = SubmissionEntry {
+                cdw0: ADMIN_CREATE_IO_CQ,
+                prp1: queue.cq as usize as u64,
+                cdw10: ((size as u32 - 1) << 16) | qid as u32,
+                cdw11: irq | 1,
+                ..Default::default()
+            };
+            self.admin_command(cmd)?;
+            queue.vector = vector;
+            // The submission queue completes to the completion queue of
+            // the same identifier (CDW11 bits 31:16).
+            let cmd = SubmissionEntry {
+                cdw0: ADMIN_CREATE_IO_SQ,
+                prp1: queue.sq as usize as u64,
+                cdw10: ((size as u32 - 1) << 16) | qid as u32,
+                cdw11: (qid as u32) << 16 | 1,
+                ..Default::default()
+            };
+            self.admin_command(cmd)?;
+            match vector {
+                Some(vector) => println!("NVMe queue {} -> vector {}.", qid, vector),
+                None => println!("NVMe queue {} is polled.", qid),
+            }
+            self.io[i] = Some(queue);
+            self.io_queues = i + 1;
+        }
+        Some(self.io_queues)
+    }
+
+    /// # Overview
+    /// Read blocks from a namespace into a buffer.
+    /// # Arguments
+    /// * `nsid` - the namespace to read from
+    /// * `lba` - the first logical block to read
+    /// * `count` - the number of blocks to read
+    /// * `buf` - where to put the data. It must be at least `count` blocks
+    ///   long and 4-byte aligned.
+    /// # Returns
+    /// `true` if every block was read, `false` otherwise.
+    pub fn read_blocks(&mut self, nsid: u32, lba: u64, count: usize, buf: &mut [u8]) -> bool {
+        self.transfer(NVM_READ, nsid, lba, count, buf.as_mut_ptr(), buf.len())
+    }
+
+    /// # Overview
+    /// Write blocks from a buffer to a namespace.
+    /// # Arguments
+    /// * `nsid` - the namespace to write to
+    /// * `lba` - the first logical block to write
+    /// * `count` - the number of blocks to write
+    /// * `buf` - the data to write. It must be at least `count` blocks
+    ///   long and 4-byte aligned.
+    /// # Returns
+    /// `true` if every block was written, `false` otherwise.
+    pub fn write_blocks(&mut self, nsid: u32, lba: u64, count: usize, buf: &[u8]) -> bool {
+        self.transfer(NVM_WRITE, nsid, lba, count, buf.as_ptr() as *mut u8, buf.len())
+    }
+
+    /// # Overview
+    /// Split a read or write into as many commands as the controller's
+    /// MDTS and our single PRP list allow, and run each of them.
+    fn transfer(
+        &mut self,
+        opcode: u32,
+        nsid: u32,
+        lba: u64,
+        count: usize,
+        buf: *mut u8,
+        len: usize,
+    ) -> bool {
+        let ns = match self.namespace(nsid) {
+            Some(ns) => ns,
+            None => {
+                println!("NVMe namespace {} not found.", nsid);
+                return false;
+            }
+        };
+        if self.io_queues == 0 {
+            println!("NVMe has no I/O queues.");
+            return false;
+        }
+        let block_size = ns.block_size();
+        if count * block_size > len {
+            println!("NVMe buffer of {} bytes is too small for {} blocks.", len, count);
+            return false;
+        }
+        if lba + count as u64 > ns.nsze {
+            println!(
+                "NVMe blocks {}..{} are past the end of namespace {}.",
+                lba,
+                lba + count as u64,
+                nsid
+            );
+            return false;
+        }
+        if buf as usize & 3 != 0 {
+            // PRP entries must be dword aligned.
+            println!("NVMe buffer 0x{:08x} is not 4-byte aligned.", buf as usize);
+            return false;
+        }
+
+        let mut max_bytes = PRP_ENTRIES_PER_PAGE * PAGE_SIZE;
+        if let Some(mdts) = self.ctrl.and_then(|ctrl| ctrl.max_transfer()) {
+            max_bytes = max_bytes.min(mdts);
+        }
+        // NLB is a 16-bit, 0's based field.
+        let max_blocks = (max_bytes / block_size).min(0x1_0000);
+
+        let mut done = 0;
+        while done < count {
+            let blocks = (count - done).min(max_blocks);
+            let addr = buf as usize + done * block_size;
+            let queue = self.io[self.next_io].as_mut().unwrap();
+            self.next_io = (self.next_io + 1) % self.io_queues;
+
+            let (prp1, prp2) = build_prps(addr, blocks * block_size, queue.prp_list);
+            let slba = lba + done as u64;
+            let cmd = SubmissionEntry {
+                cdw0: opcode,
+                nsid,
+                prp1,
+                prp2,
+                cdw10: slba as u32,
+                cdw11: (slba >> 32) as u32,
+                cdw12: blocks as u32 - 1,
+                ..Default::default()
+            };
+            if queue.execute(cmd, self.timeout).is_none() {
+                return false;
+            }
+            done += blocks;
+        }
+        true
+    }
+}
+
+/// # Overview
+/// Build the PRP entries for a physically contiguous buffer.
+/// PRP1 may start anywhere in a page. Every entry after that must be page
+/// aligned, so if the buffer spans more than two pages PRP2 points to a
+/// list of the remaining pages.
+/// # Arguments
+/// * `addr` - the physical address of the buffer
+/// * `bytes` - the length of the buffer. This must fit in one PRP list.
+/// * `list` - a page to write the PRP list into
+/// # Returns
+/// `(prp1, prp2)`
+fn build_prps(addr: usize, bytes: usize, list: *mut u64) -> (u64, u64) {
+    let first = PAGE_SIZE - (addr & (PAGE_SIZE - 1));
+    if bytes <= first {
+        return (addr as u64, 0);
+    }
+    let next = align_down(addr) + PAGE_SIZE;
+    let remaining = bytes - first;
+    if remaining <= PAGE_SIZE {
+        return (addr as u64, next as u64);
+    }
+    let pages = remaining.div_ceil(PAGE_SIZE);
+    assert!(pages <= PRP_ENTRIES_PER_PAGE);
+    for i in 0..pages {
+        unsafe {
+            list.add(i).write((next + i * PAGE_SIZE) as u64);
+        }
+    }
+    (addr as u64, list as usize as u64)
+}
+
+/// # Overview
+/// The MSI handler for every NVMe vector. Called from
+/// `imsic::imsic_handle` when a vector's EIID is popped. Reaps every
+/// completion queue that interrupts on this vector.
+/// # Arguments
+/// * `data` - the controller index in the upper 16 bits and the vector
+///   in the lower 16 bits
+fn nvme_vector_handler(data: usize) {
+    let nvme = match controller(data >> 16) {
+        Some(nvme) => nvme,
+        None => return,
+    };
+    let vector = Some(data & 0xFFFF);
+    if nvme.admin.vector == vector {
+        nvme.admin.reap();
+    }
+    for queue in nvme.io.iter_mut().flatten() {
+        if queue.vector == vector {
+            queue.reap();
+        }
+    }
+}
+
+/// # Overview
+/// Get an NVMe controller by index in the order they were set up.
+pub fn controller<'a>(which: usize) -> Option<&'a mut Nvme> {
+    unsafe { (*addr_of_mut!(NVME_DEVICES)).get_mut(which)?.as_mut() }
+}
+
+/// # Overview
+/// Read blocks from a namespace on the first NVMe controller.
+/// See `Nvme::read_blocks`.
+pub fn read_blocks(nsid: u32, lba: u64, count: usize, buf: &mut [u8]) -> bool {
+    match controller(0) {
+        Some(nvme) => nvme.read_blocks(nsid, lba, count, buf),
+        None => {
+            println!("No NVMe controller.");
+            false
+        }
+    }
+}
+
+/// # Overview
+/// Write blocks to a namespace on the first NVMe controller.
+/// See `Nvme::write_blocks`.
+pub fn write_blocks(nsid: u32, lba: u64, count: usize, buf: &[u8]) -> bool {
+    match controller(0) {
+        Some(nvme) => nvme.write_blocks(nsid, lba, count, buf),
+        None => {
+            println!("No NVMe controller.");
+            false
+        }
+    }
+}
+
+const NO_QUEUE: Option<QueuePair> = None;
+const NO_NVME: Option<Nvme> = None;
+const MAX_NVME_DEVICES: usize = 4;
+static mut NVME_DEVICES: [Option<Nvme>; MAX_NVME_DEVICES] = [NO_NVME; MAX_NVME_DEVICES];
+
+// Mass storage (0x01), non-volatile memory (0x08), NVMe (0x02)
+const NVME_CLASS: u32 = 0x01_08_02;
+static NVME_IDS: [PciDeviceId; 1] = [PciDeviceId::class(NVME_CLASS, 0xFF_FF_FF)];
+
+struct NvmeDriver;
+
+impl PciDriver for NvmeDriver {
+    fn name(&self) -> &'static str {
+        "nvme"
+    }
+
+    fn id_table(&self) -> &'static [PciDeviceId] {
+        &NVME_IDS
+    }
+
+    fn probe(&self, dev: &mut PciDevice, _id: &PciDeviceId) -> bool {
+        let base = match dev.bar(0) {
+            Some(base) => base,
+            None => return false,
+        };
+        match nvme_setup(base, dev.msi) {
+            Some(index) => {
+                dev.driver_data = index;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&self, dev: &mut PciDevice) {
+        nvme_remove(dev.driver_data);
+    }
+}
+
+static NVME_DRIVER: NvmeDriver = NvmeDriver;
+
 pub fn init() {
     if unsafe { NVME_INITIALIZED } {
         println!("NVMe already initialized.");
@@ -16,14 +935,9 @@ pub fn init() {
         println!("PCI has not yet been initialized.");
         return;
     }
-    for i in unsafe { PCI_DEVICES.iter() } {
-        if let Some(x) = *i {
-            match x {
-                PciDevice::Nvme(base) => {
-                    nvme_setup(base);
-                }
-            }
-        }
+    // This probes every NVMe controller PCI found.
+    if !pci_register_driver(&NVME_DRIVER) {
+        return;
     }
 
     unsafe {
@@ -31,6 +945,218 @@ pub fn init() {
     }
 }
 
-fn nvme_setup(base: usize) {
+/// # Overview
+/// Wait for CSTS.RDY to become the given value.
+/// # Returns
+/// `true` if RDY reached `ready` before the timeout, `false` if it timed
+/// out or the controller reported a fatal status.
+fn wait_ready(regs: &NvmeRegs, ready: bool, timeout: usize) -> bool {
+    let deadline = timer_deadline(timeout.max(1) as u64 * TIMEOUT_UNIT_MS);
+    loop {
+        let expired = timer_expired(deadline);
+        let csts = regs.csts.read();
+        if csts & CSTS_CFS != 0 {
+            println!("NVMe controller fatal status.");
+            return false;
+        }
+        if (csts & CSTS_RDY != 0) == ready {
+            return true;
+        }
+        if expired {
+            return false;
+        }
+    }
+}
+
+/// # Overview
+/// Reset the controller, create the admin queues, and enable it.
+/// # Arguments
+/// * `index` - the slot in NVME_DEVICES this controller will go into
+/// * `base` - the MMIO address of BAR 0
+/// * `msi` - the controller's MSI or MSI-X vectors, if it has either
+/// # Returns
+/// The controller if it came up, `None` otherwise
+fn nvme_reset(index: usize, base: usize, msi: Option<MsiVectors>) -> Option<Nvme> {
+    let regs = NvmeRegs::as_ref(base);
+    let cap = regs.cap.read();
+    let mqes = (cap & 0xFFFF) as usize + 1;
+    let timeout = (cap >> 24 & 0xFF) as usize;
+    let dstrd = 4 << (cap >> 32 & 0xF);
+    let css = cap >> 37 & 0xFF;
+    let mpsmin = cap >> 48 & 0xF;
+    if css & 1 == 0 {
+        println!("NVMe controller does not support the NVM command set.");
+        return None;
+    }
+    if mpsmin != 0 {
+        // We only hand out 4 KiB pages.
+        println!("NVMe controller does not support 4 KiB pages.");
+        return None;
+    }
+
+    // Disable the controller and wait for it to acknowledge.
+    let cc = regs.cc.read();
+    if cc & CC_EN != 0 {
+        regs.cc.write(cc & !CC_EN);
+    }
+    if !wait_ready(regs, false, timeout) {
+        println!("NVMe controller did not reset.");
+        return None;
+    }
+
+    let qsize = ADMIN_QUEUE_SIZE.min(mqes);
+    let admin = QueuePair::new(base, dstrd, 0, qsize)?;
+    // AQA is 0's based for both the ACQS and ASQS
+    regs.aqa.write(((qsize as u32 - 1) << 16) | (qsize as u32 - 1));
+    regs.asq.write(admin.sq as usize as u64);
+    regs.acq.write(admin.cq as usize as u64);
+    // MPS = 0 (4 KiB), CSS = 0 (NVM), AMS = 0 (round robin)
+    regs.cc.write(CC_IOCQES | CC_IOSQES | CC_EN);
+    if !wait_ready(regs, true, timeout) {
+        println!("NVMe controller did not become ready.");
+        return None;
+    }
+
+    Some(Nvme {
+        index,
+        base,
+        msi,
+        eiids: [0; 1 + IO_QUEUES],
+        vectors: 0,
+        harts: [0; 1 + IO_QUEUES],
+        dstrd,
+        mqes,
+        timeout,
+        admin,
+        ctrl: None,
+        namespaces: [None; MAX_NAMESPACES],
+        io: [NO_QUEUE; IO_QUEUES],
+        io_queues: 0,
+        next_io: 0,
+    })
+}
+
+/// # Overview
+/// Reset a controller and bring up its queues.
+/// # Returns
+/// The controller's index in NVME_DEVICES, `None` if it couldn't be
+/// started
+fn nvme_setup(base: usize, msi: Option<MsiVectors>) -> Option<usize> {
     println!("NVME @ 0x{:08x}", base);
+    // The controller goes into NVME_DEVICES before we send it any commands
+    // so that nvme_vector_handler can find its queues.
+    let index = match unsafe { (*addr_of!(NVME_DEVICES)).iter().position(|i| i.is_none()) } {
+        Some(index) => index,
+        None => {
+            println!("Unable to add NVMe device.");
+            return None;
+        }
+    };
+    let nvme = match nvme_reset(index, base, msi) {
+        Some(nvme) => unsafe { NVME_DEVICES[index].insert(nvme) },
+        None => {
+            println!("Unable to start NVMe controller @ 0x{:08x}.", base);
+            return None;
+        }
+    };
+    let vs = NvmeRegs::as_ref(base).vs.read();
+    println!(
+        "NVMe {}.{} ready, {} entry admin queue, max queue entries {}.",
+        vs >> 16,
+        vs >> 8 & 0xFF,
+        nvme.admin.size,
+        nvme.mqes
+    );
+    // The admin completion queue always uses vector 0.
+    nvme.setup_vectors();
+    nvme.admin.vector = nvme.queue_vector(0);
+    if !nvme.identify_all() {
+        println!("Unable to identify NVMe controller @ 0x{:08x}.", base);
+        nvme_remove(index);
+        return None;
+    }
+    match nvme.create_io_queues() {
+        Some(count) => {
+            println!("NVMe created {} I/O queue pairs.", count);
+            nvme.spread_vectors();
+        }
+        None => println!("Unable to create NVMe I/O queues."),
+    }
+    Some(index)
+}
+
+/// # Overview
+/// Shut a controller down and forget about it. The queue pages are not
+/// given back since the page allocator can't free.
+fn nvme_remove(index: usize) {
+    let nvme = match unsafe { NVME_DEVICES[index].take() } {
+        Some(nvme) => nvme,
+        None => return,
+    };
+    if let Some(mut msi) = nvme.msi {
+        for (vector, &eiid) in nvme.eiids.iter().enumerate().take(nvme.vectors) {
+            msi.mask(vector);
+            imsic_disable_on(nvme.harts[vector], PrivMode::Machine, eiid);
+            imsic_free(nvme.harts[vector], PrivMode::Machine, eiid);
+        }
+    }
+    // Clearing CC.EN deletes every queue on the controller.
+    let regs = NvmeRegs::as_ref(nvme.base);
+    regs.cc.modify(|cc| cc & !CC_EN);
+    if !wait_ready(regs, false, nvme.timeout) {
+        println!("NVMe controller @ 0x{:08x} did not shut down.", nvme.base);
+    }
+}
+
+/// # Overview
+/// Print the identify data for every NVMe controller and its namespaces.
+/// This is here so we can make sure we are talking to the disk `run.sh`
+/// attached.
+pub fn info() {
+    if unsafe { !NVME_INITIALIZED } {
+        println!("NVMe has not yet been initialized.");
+        return;
+    }
+    for (i, nvme) in unsafe { (*addr_of!(NVME_DEVICES)).iter().enumerate() } {
+        let nvme = match nvme {
+            Some(nvme) => nvme,
+            None => continue,
+        };
+        let ctrl = match nvme.ctrl {
+            Some(ctrl) => ctrl,
+            None => continue,
+        };
+        println!("nvme{} @ 0x{:08x}", i, nvme.base);
+        println!("  Model:      {}", ctrl.model());
+        println!("  Serial:     {}", ctrl.serial());
+        println!("  Firmware:   {}", ctrl.firmware());
+        println!("  NQN:        {}", ctrl.nqn());
+        println!("  Vendor:     0x{:04x} (subsystem 0x{:04x})", ctrl.vid, ctrl.ssvid);
+        println!("  Controller: {}", ctrl.cntlid);
+        match ctrl.max_transfer() {
+            Some(mdts) => println!("  MDTS:       {} bytes", mdts),
+            None => println!("  MDTS:       unlimited"),
+        }
+        println!("  Namespaces: {}", ctrl.nn);
+        for (nsid, ns) in nvme.namespaces.iter().flatten() {
+            println!(
+                "  ns{}: {} blocks of {} bytes ({} KiB), format {} of {}",
+                nsid,
+                ns.nsze,
+                ns.block_size(),
+                ns.size() / 1024,
+                ns.flbas & 0xF,
+                ns.nlbaf as usize + 1
+            );
+            for (j, lbaf) in ns.lbaf.iter().take(ns.nlbaf as usize + 1).enumerate() {
+                println!(
+                    "    lbaf {}: {} byte blocks, {} byte metadata, rp {}",
+                    j,
+                    1usize << lbaf.lbads,
+                    lbaf.ms,
+                    lbaf.rp
+                );
+            }
+        }
+    }
 }
@@ -1,5 +1,16 @@
-use core::ptr::write_volatile;
-use crate::imsic::IMSIC_M;
+use crate::{
+    imsic::{
+        imsic_alloc, imsic_alloc_block, imsic_copy_handler, imsic_disable_on, imsic_free,
+        imsic_m, imsic_s, imsic_send, imsic_take_pending, PrivMode,
+    },
+    irq::controller,
+    pcieport,
+    volatile::Volatile,
+};
+use core::{
+    mem::ManuallyDrop,
+    ptr::{addr_of, addr_of_mut},
+};
 
 // ECAM is hard coded in virt.c to 0x3000_0000
 const PCI_ECAM_BASE: usize = 0x3000_0000;
@@ -7,264 +18,2165 @@ const PCI_ECAM_BASE: usize = 0x3000_0000;
 // and 0x4_0000_0000. Since we're using RV32I, opt
 // for the 32-bit address to avoid a dual cycle read/write
 // from PCI system.
-const PCI_BAR_BASE: usize = 0x4000_0000;
+const PCI_BAR_BASE: u64 = 0x4000_0000;
+// The 32-bit window is 1 GiB. We give the bottom half to
+// non-prefetchable memory and the top half to prefetchable memory.
+const PCI_BAR_SIZE: u64 = 0x4000_0000;
+const PCI_PREF_BASE: u64 = PCI_BAR_BASE + PCI_BAR_SIZE / 2;
+// RV64 can reach the 64-bit window, which only 64-bit prefetchable
+// BARs (and bridge windows holding only those) go into.
+#[cfg(target_pointer_width = "64")]
+const PCI_BAR64_BASE: u64 = 0x4_0000_0000;
+#[cfg(target_pointer_width = "64")]
+const PCI_BAR64_SIZE: u64 = 0x4_0000_0000;
 
 // Bits for the command register in ECAM space
 const COMMAND_REG_MEM_SPACE: u16 = 1 << 1;
 const COMMAND_REG_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_REG_INTX_DISABLE: u16 = 1 << 10;
+
+// QEMU virt wires INTA-INTD of the root bus to sources 32-35 on the
+// APLIC or the PLIC. Like the UART, each line uses its source number as
+// its EIID when there are IMSICs.
+const PCI_INTX_IRQ_BASE: u32 = 32;
+const PCI_INTX_LINES: usize = 4;
+// How many handlers can share one INTx line
+const MAX_INTX_HANDLERS: usize = 8;
+
+/// An INTx handler gets the data it was registered with and returns
+/// `true` if its device was the one interrupting.
+pub type IntxHandler = fn(usize) -> bool;
+
+#[derive(Clone, Copy)]
+struct IntxAction {
+    handler: IntxHandler,
+    data: usize,
+}
+
+static mut INTX_HANDLERS: [[Option<IntxAction>; MAX_INTX_HANDLERS]; PCI_INTX_LINES] =
+    [[None; MAX_INTX_HANDLERS]; PCI_INTX_LINES];
+
+// Bit 7 of the header type means the device has functions 1-7.
+const HEADER_TYPE_MULTIFUNCTION: u8 = 1 << 7;
+
+// Bridge memory windows have a 1 MiB granularity.
+const BRIDGE_WINDOW_ALIGN: u64 = 1 << 20;
+
+// BAR bits
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+// The low nibble of the prefetchable base says whether the bridge
+// supports a 64-bit prefetchable window.
+const BRIDGE_PREF_64: u16 = 1;
+
+// What we leave in each window of a hot-plug port, so there is room for
+// whatever gets plugged in later.
+const HOTPLUG_MEM_SIZE: u64 = 4 << 20;
+const HOTPLUG_PREF_SIZE: u64 = 4 << 20;
+
+// PCI Express capability and the bits we look at to find hot-plug slots
+pub const CAP_PCIE: u8 = 0x10;
+const PCIE_CAPS_SLOT: u16 = 1 << 8;
+const PCIE_SLOT_CAPS_HPC: u32 = 1 << 6;
 
 pub static mut PCI_INITIALIZED: bool = false;
 
-pub const MAX_PCI_DEVICES: usize = 4;
+pub const MAX_PCI_DEVICES: usize = 16;
 pub static mut PCI_DEVICES: [Option<PciDevice>; MAX_PCI_DEVICES] = [None; MAX_PCI_DEVICES];
 
+pub const MAX_PCI_DRIVERS: usize = 8;
+static mut PCI_DRIVERS: [Option<&'static dyn PciDriver>; MAX_PCI_DRIVERS] = [None; MAX_PCI_DRIVERS];
+
+// The most capabilities we remember for one function
+pub const MAX_PCI_CAPS: usize = 16;
+
+/// A capability in a function's capability list.
+#[derive(Clone, Copy, Default)]
+pub struct PciCap {
+    pub id: u8,
+    // Offset into configuration space
+    pub offset: u8,
+}
+
+/// A function that is enabled and can be handed to a driver. Drivers get
+/// everything they need from here instead of going back to ECAM.
+#[derive(Clone, Copy)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    // Base class << 16 | subclass << 8 | programming interface
+    pub class: u32,
+    pub revision: u8,
+    pub header_type: u8,
+    // Index in PCI_FUNCTIONS
+    pub function: usize,
+    pub bars: [PciBar; 6],
+    // MSI or MSI-X, left disabled/masked for the driver to set up
+    pub msi: Option<MsiVectors>,
+    // The source INTx is wired to, if the function has an INTx pin
+    pub intx: Option<u32>,
+    pub caps: [PciCap; MAX_PCI_CAPS],
+    pub num_caps: usize,
+    pub ext_caps: [PciExtCap; MAX_PCI_EXT_CAPS],
+    pub num_ext_caps: usize,
+    // The driver bound to this device and whatever it wants to remember
+    pub driver: Option<&'static dyn PciDriver>,
+    pub driver_data: usize,
+}
+
+impl PciDevice {
+    /// The address a memory BAR was given, if it was given one.
+    pub fn bar(&self, which: usize) -> Option<usize> {
+        let bar = self.bars.get(which)?;
+        match bar.size {
+            0 => None,
+            _ => bar.addr.map(|addr| addr as usize),
+        }
+    }
+
+    /// The capabilities we found, in list order.
+    pub fn caps(&self) -> &[PciCap] {
+        &self.caps[..self.num_caps]
+    }
+
+    /// # Overview
+    /// Find a capability by its id.
+    /// # Returns
+    /// The capability's offset in configuration space
+    pub fn find_cap(&self, id: u8) -> Option<usize> {
+        self.caps().iter().find(|c| c.id == id).map(|c| c.offset as usize)
+    }
+
+    /// The extended capabilities we found, in list order.
+    pub fn ext_caps(&self) -> &[PciExtCap] {
+        &self.ext_caps[..self.num_ext_caps]
+    }
+
+    /// # Overview
+    /// Find an extended capability by its id.
+    /// # Returns
+    /// The capability's offset in configuration space
+    pub fn find_ext_cap(&self, id: u16) -> Option<usize> {
+        self.ext_caps().iter().find(|c| c.id == id).map(|c| c.offset as usize)
+    }
+
+    /// Map a typed structure over a capability.
+    fn cap<'a, T>(&self, id: u8) -> Option<&'a T> {
+        let offset = self.find_cap(id)?;
+        unsafe { (self.config().add(offset) as *const T).as_ref() }
+    }
+
+    pub fn pcie<'a>(&self) -> Option<&'a PcieCapability> {
+        self.cap(CAP_PCIE)
+    }
+
+    /// Map a typed structure over an extended capability.
+    fn ext_cap<'a, T>(&self, id: u16) -> Option<&'a T> {
+        let offset = self.find_ext_cap(id)?;
+        unsafe { (self.config().add(offset) as *const T).as_ref() }
+    }
+
+    pub fn aer<'a>(&self) -> Option<&'a AerCapability> {
+        self.ext_cap(EXT_CAP_AER)
+    }
+
+    pub fn serial_number(&self) -> Option<u64> {
+        self.ext_cap::<DsnCapability>(EXT_CAP_DSN).map(|dsn| dsn.serial())
+    }
+
+    pub fn acs<'a>(&self) -> Option<&'a AcsCapability> {
+        self.ext_cap(EXT_CAP_ACS)
+    }
+
+    pub fn sriov<'a>(&self) -> Option<&'a SriovCapability> {
+        self.ext_cap(EXT_CAP_SRIOV)
+    }
+
+    /// A pointer to the start of this function's configuration space.
+    pub fn config(&self) -> *mut u8 {
+        Ecam::as_mut_ptr(self.bus as usize, self.slot as usize, self.func as usize) as *mut u8
+    }
+}
+
+// Matches any vendor or device in a PciDeviceId.
+pub const PCI_ANY_ID: u16 = 0xFFFF;
+
+/// One entry in a driver's match table. A device matches if the vendor
+/// and device match (or are PCI_ANY_ID) and the class code matches under
+/// `class_mask`.
+#[derive(Clone, Copy)]
+pub struct PciDeviceId {
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u32,
+    pub class_mask: u32,
+}
+
+impl PciDeviceId {
+    /// Match one vendor and device.
+    pub const fn device(vendor: u16, device: u16) -> Self {
+        Self {
+            vendor,
+            device,
+            class: 0,
+            class_mask: 0,
+        }
+    }
+
+    /// Match the class code bits selected by `class_mask`.
+    pub const fn class(class: u32, class_mask: u32) -> Self {
+        Self {
+            vendor: PCI_ANY_ID,
+            device: PCI_ANY_ID,
+            class,
+            class_mask,
+        }
+    }
+
+    pub fn matches(&self, dev: &PciDevice) -> bool {
+        (self.vendor == PCI_ANY_ID || self.vendor == dev.vendor_id)
+            && (self.device == PCI_ANY_ID || self.device == dev.device_id)
+            && (dev.class ^ self.class) & self.class_mask == 0
+    }
+}
+
+/// A driver for PCI devices. Drivers are registered with
+/// `pci_register_driver` and are probed against every device that
+/// matches an entry in their `id_table`.
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The devices this driver can handle.
+    fn id_table(&self) -> &'static [PciDeviceId];
+
+    /// # Overview
+    /// Take over a device.
+    /// # Arguments
+    /// * `dev` - the device, which the driver may keep data in
+    /// * `id` - the entry in `id_table` that matched
+    /// # Returns
+    /// `true` if the driver now owns the device
+    fn probe(&self, dev: &mut PciDevice, id: &PciDeviceId) -> bool;
+
+    /// Let go of a device the driver probed.
+    fn remove(&self, dev: &mut PciDevice);
+}
+
+/// A function found during enumeration.
 #[derive(Clone, Copy)]
-pub enum PciDevice {
-    Nvme(usize)
+pub struct PciFunction {
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    // The header type without the multifunction bit
+    pub header_type: u8,
+    // The index in PCI_FUNCTIONS of the bridge this function is behind,
+    // or None if it is on the root bus.
+    pub parent: Option<usize>,
+    // For bridges, the range of buses behind the bridge
+    pub secondary_bus: u8,
+    pub subordinate_bus: u8,
+    // Type 0 functions have six BARs, bridges have two.
+    pub bars: [PciBar; 6],
+    // For bridges, the memory windows forwarded to the secondary bus
+    pub mem: PciWindow,
+    pub pref: PciWindow,
+    // The alignment each window needs to be placed at: at least
+    // BRIDGE_WINDOW_ALIGN, and no less than anything behind it needs
+    pub mem_align: u64,
+    pub pref_align: u64,
+    // Whether the prefetchable window is above 4 GiB
+    pub pref64: bool,
+    // For bridges, whether the slot behind it supports hot-plug
+    pub hotplug: bool,
+}
+
+/// A memory BAR. A 64-bit BAR takes two BAR registers, and the second
+/// one is left with a size of 0.
+#[derive(Clone, Copy, Default)]
+pub struct PciBar {
+    // 0 means unimplemented (or I/O space, which we don't support)
+    pub size: u64,
+    // None if we couldn't find room for the BAR
+    pub addr: Option<u64>,
+    pub is64: bool,
+    pub prefetchable: bool,
+}
+
+impl PciBar {
+    fn kind(&self) -> ResourceKind {
+        match (self.prefetchable, self.is64) {
+            (false, _) => ResourceKind::Mem,
+            (true, false) => ResourceKind::Pref32,
+            (true, true) => ResourceKind::Pref64,
+        }
+    }
+}
+
+/// A range of MMIO space we hand out from the bottom up.
+#[derive(Clone, Copy, Default)]
+pub struct PciWindow {
+    pub base: u64,
+    pub size: u64,
+    next: u64,
+}
+
+impl PciWindow {
+    const fn new(base: u64, size: u64) -> Self {
+        Self {
+            base,
+            size,
+            next: base,
+        }
+    }
+
+    /// # Overview
+    /// Carve an aligned range out of the window.
+    /// # Arguments
+    /// * `size` - the number of bytes
+    /// * `align` - the alignment, which must be a power of two
+    /// # Returns
+    /// The address, or `None` if the window doesn't have enough left.
+    fn alloc(&mut self, size: u64, align: u64) -> Option<u64> {
+        let addr = self.next.checked_add(align - 1)? & !(align - 1);
+        let end = addr.checked_add(size)?;
+        if end > self.base + self.size {
+            return None;
+        }
+        self.next = end;
+        Some(addr)
+    }
+
+    /// The number of bytes handed out so far.
+    fn used(&self) -> u64 {
+        self.next - self.base
+    }
+
+    /// # Overview
+    /// Note that a range is already in use, so nothing is handed out
+    /// below its end. Ranges outside of the window are ignored.
+    fn reserve(&mut self, addr: u64, size: u64) {
+        if addr >= self.base && addr < self.base + self.size {
+            self.next = self.next.max(addr + size);
+        }
+    }
+}
+
+/// Where a piece of MMIO space has to come from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ResourceKind {
+    // Non-prefetchable. Bridges only forward this below 4 GiB.
+    Mem,
+    // Prefetchable, but must be below 4 GiB
+    Pref32,
+    // Prefetchable and may be anywhere
+    Pref64,
 }
 
-fn pci_add_device(dev: PciDevice) {
+// Resource::bar values for a bridge's windows instead of a BAR
+const RESOURCE_MEM_WINDOW: usize = 6;
+const RESOURCE_PREF_WINDOW: usize = 7;
+
+/// A request for MMIO space from a BAR or a bridge window.
+#[derive(Clone, Copy)]
+struct Resource {
+    kind: ResourceKind,
+    size: u64,
+    align: u64,
+    // Index in PCI_FUNCTIONS
+    func: usize,
+    // 0-5 for a BAR, or RESOURCE_MEM_WINDOW/RESOURCE_PREF_WINDOW
+    bar: usize,
+}
+
+// The most resources a single bus can have
+const MAX_BUS_RESOURCES: usize = 64;
+
+pub const MAX_PCI_FUNCTIONS: usize = 32;
+pub static mut PCI_FUNCTIONS: [Option<PciFunction>; MAX_PCI_FUNCTIONS] = [None; MAX_PCI_FUNCTIONS];
+
+/// Get a function by its index in PCI_FUNCTIONS, which must be in use.
+pub fn pci_function<'a>(index: usize) -> &'a mut PciFunction {
+    unsafe { PCI_FUNCTIONS[index].as_mut().unwrap() }
+}
+
+/// # Overview
+/// Find a device by where it is.
+/// # Returns
+/// The device and its index in PCI_DEVICES
+pub fn pci_find_device<'a>(bus: u8, slot: u8, func: u8) -> Option<(usize, &'a mut PciDevice)> {
+    unsafe {
+        (*addr_of_mut!(PCI_DEVICES))
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, d)| Some((i, d.as_mut()?)))
+            .find(|(_, d)| d.bus == bus && d.slot == slot && d.func == func)
+    }
+}
+
+/// # Overview
+/// Record a function we found.
+/// # Returns
+/// The function's index in PCI_FUNCTIONS, `None` if the table is full
+fn pci_add_function(func: PciFunction) -> Option<usize> {
     unsafe {
-        for i in PCI_DEVICES.iter_mut() {
-            if i.is_none() {
-                *i = Some(dev);
-                return;
+        let index = (*addr_of!(PCI_FUNCTIONS)).iter().position(|i| i.is_none());
+        match index {
+            Some(index) => PCI_FUNCTIONS[index] = Some(func),
+            None => println!("Unable to add PCI function."),
+        }
+        index
+    }
+}
+
+/// # Overview
+/// Add a device and try to find a driver for it.
+/// # Returns
+/// The device's index in PCI_DEVICES, `None` if the table is full
+fn pci_add_device(dev: PciDevice) -> Option<usize> {
+    let index = unsafe { (*addr_of!(PCI_DEVICES)).iter().position(|i| i.is_none()) };
+    match index {
+        Some(index) => {
+            unsafe {
+                PCI_DEVICES[index] = Some(dev);
             }
+            pci_bind(index);
+        }
+        None => println!("Unable to add PCI device."),
+    }
+    index
+}
+
+/// # Overview
+/// Unbind a device from its driver and forget about it.
+pub fn pci_remove_device(index: usize) {
+    let dev = unsafe { (*addr_of_mut!(PCI_DEVICES)).get_mut(index) };
+    if let Some(dev) = dev.and_then(|d| d.as_mut()) {
+        if let Some(drv) = dev.driver.take() {
+            drv.remove(dev);
+        }
+        unsafe {
+            PCI_DEVICES[index] = None;
+        }
+    }
+}
+
+/// # Overview
+/// Offer an unbound device to each registered driver until one takes it.
+fn pci_bind(index: usize) {
+    let dev = match unsafe { PCI_DEVICES[index].as_mut() } {
+        Some(dev) if dev.driver.is_none() => dev,
+        _ => return,
+    };
+    for drv in unsafe { (*addr_of!(PCI_DRIVERS)).iter().flatten() } {
+        if pci_probe(*drv, dev) {
+            return;
+        }
+    }
+}
+
+/// # Overview
+/// Probe a driver against a device if any of its ids match.
+/// # Returns
+/// `true` if the driver took the device
+fn pci_probe(drv: &'static dyn PciDriver, dev: &mut PciDevice) -> bool {
+    let id = match drv.id_table().iter().find(|id| id.matches(dev)) {
+        Some(id) => id,
+        None => return false,
+    };
+    if !drv.probe(dev, id) {
+        return false;
+    }
+    println!(
+        "PCI {:02x}:{:02x}.{} bound to {}.",
+        dev.bus,
+        dev.slot,
+        dev.func,
+        drv.name()
+    );
+    dev.driver = Some(drv);
+    true
+}
+
+/// # Overview
+/// Register a driver and probe it against every device without one.
+/// # Returns
+/// `false` if there is no room for the driver
+pub fn pci_register_driver(drv: &'static dyn PciDriver) -> bool {
+    let slot = match unsafe { (*addr_of_mut!(PCI_DRIVERS)).iter_mut().find(|d| d.is_none()) } {
+        Some(slot) => slot,
+        None => {
+            println!("Unable to register PCI driver {}.", drv.name());
+            return false;
+        }
+    };
+    *slot = Some(drv);
+    for dev in unsafe { (*addr_of_mut!(PCI_DEVICES)).iter_mut().flatten() } {
+        if dev.driver.is_none() {
+            pci_probe(drv, dev);
         }
     }
-    println!("Unable to add PCI device.");
+    true
 }
 
+/// # Overview
+/// Remove a driver from every device it is bound to and unregister it.
+pub fn pci_unregister_driver(drv: &'static dyn PciDriver) {
+    for dev in unsafe { (*addr_of_mut!(PCI_DEVICES)).iter_mut().flatten() } {
+        if dev.driver.is_some_and(|d| core::ptr::addr_eq(d, drv)) {
+            dev.driver = None;
+            drv.remove(dev);
+        }
+    }
+    for slot in unsafe { (*addr_of_mut!(PCI_DRIVERS)).iter_mut() } {
+        if slot.is_some_and(|d| core::ptr::addr_eq(d, drv)) {
+            *slot = None;
+        }
+    }
+}
 
 #[repr(C)]
-#[derive(Copy, Clone)]
 struct Type0Ecam {
-    pub bar: [u32; 6],
-    pub cardbus_cis_pointer: u32,
-    pub sub_vendor_id: u16,
-    pub sub_device_id: u16,
-    pub expansion_rom_addr: u32,
-    pub capes_pointer: u8,
-    pub reserved0: [u8; 3],
-    pub reserved1: u32,
-    pub interrupt_line: u8,
-    pub interrupt_pin: u8,
-    pub min_gnt: u8,
-    pub max_lat: u8,
+    pub bar: [Volatile<u32>; 6],
+    pub cardbus_cis_pointer: Volatile<u32>,
+    pub sub_vendor_id: Volatile<u16>,
+    pub sub_device_id: Volatile<u16>,
+    pub expansion_rom_addr: Volatile<u32>,
+    pub capes_pointer: Volatile<u8>,
+    pub reserved0: [Volatile<u8>; 3],
+    pub reserved1: Volatile<u32>,
+    pub interrupt_line: Volatile<u8>,
+    pub interrupt_pin: Volatile<u8>,
+    pub min_gnt: Volatile<u8>,
+    pub max_lat: Volatile<u8>,
 }
 
 #[repr(C)]
-#[derive(Copy, Clone)]
 struct Type1Ecam {
-    pub bar: [u32; 2],
-    pub primary_bus_no: u8,
-    pub secondary_bus_no: u8,
-    pub subordinate_bus_no: u8,
-    pub secondary_latency_timer: u8,
-    pub io_base: u8,
-    pub io_limit: u8,
-    pub secondary_status: u16,
-    pub memory_base: u16,
-    pub memory_limit: u16,
-    pub prefetch_memory_base: u16,
-    pub prefetch_memory_limit: u16,
-    pub prefetch_base_upper: u32,
-    pub prefetch_limit_upper: u32,
-    pub io_base_upper: u16,
-    pub io_limit_upper: u16,
-    pub capes_pointer: u8,
-    pub reserved0: [u8; 3],
-    pub expansion_rom_addr: u32,
-    pub interrupt_line: u8,
-    pub interrupt_pin: u8,
-    pub bridge_control: u16,
+    pub bar: [Volatile<u32>; 2],
+    pub primary_bus_no: Volatile<u8>,
+    pub secondary_bus_no: Volatile<u8>,
+    pub subordinate_bus_no: Volatile<u8>,
+    pub secondary_latency_timer: Volatile<u8>,
+    pub io_base: Volatile<u8>,
+    pub io_limit: Volatile<u8>,
+    pub secondary_status: Volatile<u16>,
+    pub memory_base: Volatile<u16>,
+    pub memory_limit: Volatile<u16>,
+    pub prefetch_memory_base: Volatile<u16>,
+    pub prefetch_memory_limit: Volatile<u16>,
+    pub prefetch_base_upper: Volatile<u32>,
+    pub prefetch_limit_upper: Volatile<u32>,
+    pub io_base_upper: Volatile<u16>,
+    pub io_limit_upper: Volatile<u16>,
+    pub capes_pointer: Volatile<u8>,
+    pub reserved0: [Volatile<u8>; 3],
+    pub expansion_rom_addr: Volatile<u32>,
+    pub interrupt_line: Volatile<u8>,
+    pub interrupt_pin: Volatile<u8>,
+    pub bridge_control: Volatile<u16>,
 }
 
 #[repr(C)]
 union TypeXEcam {
-    pub type0: Type0Ecam,
-    pub type1: Type1Ecam,
+    pub type0: ManuallyDrop<Type0Ecam>,
+    pub type1: ManuallyDrop<Type1Ecam>,
 }
 
 #[repr(C)]
-struct Capability {
-    pub id: u8,
-    pub next: u8,
+pub struct Capability {
+    pub id: Volatile<u8>,
+    pub next: Volatile<u8>,
+}
+
+/// The part every capability starts with. What `control` means depends
+/// on the capability.
+#[repr(C)]
+struct CapabilityHeader {
+    pub cap: Capability,
+    pub control: Volatile<u16>,
 }
 
 #[repr(C)]
 struct Ecam {
-    pub vendor_id: u16,
-    pub device_id: u16,
-    pub command_reg: u16,
-    pub status_reg: u16,
-    pub revision_id: u8,
-    pub prog_if: u8,
-    pub class_subcode: u8,
-    pub class_basecode: u8,
-    pub cacheline_size: u8,
-    pub latency_timer: u8,
-    pub header_type: u8,
-    pub bist: u8,
+    pub vendor_id: Volatile<u16>,
+    pub device_id: Volatile<u16>,
+    pub command_reg: Volatile<u16>,
+    pub status_reg: Volatile<u16>,
+    pub revision_id: Volatile<u8>,
+    pub prog_if: Volatile<u8>,
+    pub class_subcode: Volatile<u8>,
+    pub class_basecode: Volatile<u8>,
+    pub cacheline_size: Volatile<u8>,
+    pub latency_timer: Volatile<u8>,
+    pub header_type: Volatile<u8>,
+    pub bist: Volatile<u8>,
     pub typex: TypeXEcam,
 }
 impl Ecam {
-    pub const fn as_mut_ptr(bus: usize, slot: usize) -> *mut Self {
-        assert!(bus < 256 && slot < 32);
-        (PCI_ECAM_BASE | (bus << 20) | (slot << 15)) as *mut Self
+    pub const fn as_mut_ptr(bus: usize, slot: usize, func: usize) -> *mut Self {
+        assert!(bus < 256 && slot < 32 && func < 8);
+        (PCI_ECAM_BASE | (bus << 20) | (slot << 15) | (func << 12)) as *mut Self
+    }
+
+    pub fn as_ref<'a>(bus: usize, slot: usize, func: usize) -> &'a Self {
+        unsafe { Self::as_mut_ptr(bus, slot, func).as_ref().unwrap() }
+    }
+
+    /// Map a typed structure over whatever is at an offset, such as a
+    /// capability.
+    fn at<T>(&self, offset: usize) -> &T {
+        let cfg = self as *const Self as *const u8;
+        unsafe { &*(cfg.add(offset) as *const T) }
+    }
+
+    /// The rest of the header for a type 0 (endpoint) function.
+    pub fn type0(&self) -> &Type0Ecam {
+        unsafe { &self.typex.type0 }
     }
 
-    pub fn as_mut<'a>(bus: usize, slot: usize) -> &'a mut Self {
-        unsafe { Self::as_mut_ptr(bus, slot).as_mut().unwrap() }
+    /// The rest of the header for a type 1 (bridge) function.
+    pub fn type1(&self) -> &Type1Ecam {
+        unsafe { &self.typex.type1 }
     }
 }
 
 #[repr(C)]
 struct MsixCapability {
     pub cap: Capability,
-    pub msgcontrol: u16,
-    pub table: u32,
-    pub pba: u32,
+    pub msgcontrol: Volatile<u16>,
+    pub table: Volatile<u32>,
+    pub pba: Volatile<u32>,
 }
 
 #[repr(C)]
-struct MsixTable {
+struct MsixEntry {
+    pub addr: Volatile<u64>,
+    pub data: Volatile<u32>,
+    pub control: Volatile<u32>,
+}
+
+// Bit 0 of the vector control masks the vector.
+const MSIX_CONTROL_MASKED: u32 = 1;
+
+// Message control bits in the MSI-X capability
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+
+/// A snapshot of one MSI-X table entry.
+#[derive(Clone, Copy)]
+pub struct MsixVector {
     pub addr: u64,
     pub data: u32,
-    pub control: u32,
+    pub masked: bool,
+}
+
+/// A function's MSI-X table and pending bit array (PBA). This is just a
+/// handle to the MMIO, so it is fine to copy it around.
+#[derive(Clone, Copy)]
+pub struct MsixTable {
+    cap: *mut MsixCapability,
+    table: *mut MsixEntry,
+    // The PBA is an array of 64-bit words, but we read it 32 bits at
+    // a time so RV32 doesn't tear the read.
+    pba: *const Volatile<u32>,
+    size: usize,
+}
+
+impl MsixTable {
+    /// The number of vectors in the table.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn entry(&self, which: usize) -> &MsixEntry {
+        assert!(which < self.size);
+        unsafe { &*self.table.add(which) }
+    }
+
+    fn cap(&self) -> &MsixCapability {
+        unsafe { &*self.cap }
+    }
+
+    /// # Overview
+    /// Read one table entry.
+    /// # Arguments
+    /// * `which` - the vector number
+    pub fn vector(&self, which: usize) -> MsixVector {
+        let entry = self.entry(which);
+        MsixVector {
+            addr: entry.addr.read(),
+            data: entry.data.read(),
+            masked: entry.control.read() & MSIX_CONTROL_MASKED != 0,
+        }
+    }
+
+    /// Iterate over every entry in the table, in vector order.
+    pub fn vectors(&self) -> impl Iterator<Item = MsixVector> + '_ {
+        (0..self.size).map(move |i| self.vector(i))
+    }
+
+    /// # Overview
+    /// Point a vector at a hart's IMSIC. The vector's mask is left the
+    /// way it was, but it is masked while the address and data change
+    /// so the device never sees half of an update.
+    /// # Arguments
+    /// * `which` - the vector number
+    /// * `hart` - the hart to receive the message
+    /// * `mode` - which of the hart's interrupt files (M or S) to write
+    /// * `eiid` - the interrupt identity to write
+    pub fn set_vector(&mut self, which: usize, hart: usize, mode: PrivMode, eiid: u32) {
+        let addr = match mode {
+            PrivMode::Machine => imsic_m(hart),
+            PrivMode::Supervisor => imsic_s(hart),
+        };
+        let masked = self.is_masked(which);
+        self.mask(which);
+        let entry = self.entry(which);
+        entry.addr.write(addr as u64);
+        entry.data.write(eiid);
+        if !masked {
+            self.unmask(which);
+        }
+    }
+
+    fn set_control(&mut self, which: usize, masked: bool) {
+        self.entry(which).control.modify(|control| {
+            if masked {
+                control | MSIX_CONTROL_MASKED
+            } else {
+                control & !MSIX_CONTROL_MASKED
+            }
+        });
+    }
+
+    /// Stop a vector from sending messages. The device sets the vector's
+    /// pending bit instead.
+    pub fn mask(&mut self, which: usize) {
+        self.set_control(which, true);
+    }
+
+    /// Let a vector send messages. If it is pending, the device sends it now.
+    pub fn unmask(&mut self, which: usize) {
+        self.set_control(which, false);
+    }
+
+    pub fn is_masked(&self, which: usize) -> bool {
+        self.vector(which).masked
+    }
+
+    /// # Overview
+    /// Check the pending bit array for a vector.
+    /// # Returns
+    /// `true` if the device wanted to send this vector while it was masked
+    pub fn is_pending(&self, which: usize) -> bool {
+        assert!(which < self.size);
+        unsafe { &*self.pba.add(which / 32) }.read() >> (which % 32) & 1 == 1
+    }
+
+    /// # Overview
+    /// Set or clear the function mask. This masks every vector at once
+    /// without touching the individual vector masks.
+    pub fn set_function_mask(&mut self, masked: bool) {
+        self.cap().msgcontrol.modify(|msgcontrol| {
+            if masked {
+                msgcontrol | MSIX_FUNCTION_MASK
+            } else {
+                msgcontrol & !MSIX_FUNCTION_MASK
+            }
+        });
+    }
+
+    pub fn is_function_masked(&self) -> bool {
+        self.cap().msgcontrol.read() & MSIX_FUNCTION_MASK != 0
+    }
+}
+
+// Message control bits in the MSI capability
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_64BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASK: u16 = 1 << 8;
+
+/// The MSI capability (ID 0x05) for a function that can only send to a
+/// 32-bit address. The mask and pending bits are only there with
+/// per-vector masking.
+#[repr(C)]
+struct Msi32Capability {
+    pub cap: Capability,
+    pub msgcontrol: Volatile<u16>,
+    pub addr: Volatile<u32>,
+    pub data: Volatile<u16>,
+    _reserved: u16,
+    pub mask: Volatile<u32>,
+    pub pending: Volatile<u32>,
+}
+
+/// The MSI capability for a 64-bit capable function. The address is two
+/// registers since it is only 4-byte aligned.
+#[repr(C)]
+struct Msi64Capability {
+    pub cap: Capability,
+    pub msgcontrol: Volatile<u16>,
+    pub addr: Volatile<u32>,
+    pub addr_upper: Volatile<u32>,
+    pub data: Volatile<u16>,
+    _reserved: u16,
+    pub mask: Volatile<u32>,
+    pub pending: Volatile<u32>,
+}
+
+/// A function's MSI capability. Where the registers after the message
+/// address are depends on whether it is 64-bit capable, so this handle
+/// remembers which layout it has.
+#[derive(Clone, Copy)]
+pub struct Msi {
+    cap: *mut Capability,
+    is64: bool,
+    // Whether there are mask and pending bits
+    per_vector_mask: bool,
+    // The number of vectors the function asks for (MMC)
+    capable: usize,
+}
+
+impl Msi {
+    fn new(cap: *mut Capability) -> Self {
+        let msgcontrol = unsafe { (*(cap as *const Msi32Capability)).msgcontrol.read() };
+        Self {
+            cap,
+            is64: msgcontrol & MSI_64BIT != 0,
+            per_vector_mask: msgcontrol & MSI_PER_VECTOR_MASK != 0,
+            capable: 1 << (msgcontrol >> 1 & 7),
+        }
+    }
+
+    fn cap32(&self) -> &Msi32Capability {
+        unsafe { &*(self.cap as *const Msi32Capability) }
+    }
+
+    fn cap64(&self) -> &Msi64Capability {
+        unsafe { &*(self.cap as *const Msi64Capability) }
+    }
+
+    // Message control and the address are in the same place in both.
+    fn msgcontrol(&self) -> &Volatile<u16> {
+        &self.cap32().msgcontrol
+    }
+
+    fn data(&self) -> &Volatile<u16> {
+        match self.is64 {
+            true => &self.cap64().data,
+            false => &self.cap32().data,
+        }
+    }
+
+    fn mask_bits(&self) -> Option<&Volatile<u32>> {
+        match (self.per_vector_mask, self.is64) {
+            (false, _) => None,
+            (true, true) => Some(&self.cap64().mask),
+            (true, false) => Some(&self.cap32().mask),
+        }
+    }
+
+    fn pending_bits(&self) -> Option<&Volatile<u32>> {
+        match (self.per_vector_mask, self.is64) {
+            (false, _) => None,
+            (true, true) => Some(&self.cap64().pending),
+            (true, false) => Some(&self.cap32().pending),
+        }
+    }
+
+    /// Write the message address and data.
+    fn set_message(&self, addr: usize, eiid: u32) {
+        self.cap32().addr.write(addr as u32);
+        if self.is64 {
+            self.cap64().addr_upper.write(0);
+        }
+        self.data().write(eiid as u16);
+    }
+
+    /// The number of vectors the function supports.
+    pub fn len(&self) -> usize {
+        self.capable
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.capable == 0
+    }
+
+    /// The number of vectors currently enabled (MME).
+    pub fn enabled(&self) -> usize {
+        1 << (self.msgcontrol().read() >> 4 & 7)
+    }
+
+    pub fn can_mask(&self) -> bool {
+        self.per_vector_mask
+    }
+
+    /// # Overview
+    /// Point the whole block of vectors at a hart's IMSIC and enable MSI.
+    /// Vector `i` sends `eiid + i`, so `eiid` must be aligned to `count`.
+    /// # Arguments
+    /// * `hart` - the hart to receive the messages
+    /// * `mode` - which of the hart's interrupt files (M or S) to write
+    /// * `eiid` - the interrupt identity vector 0 sends
+    /// * `count` - how many vectors to enable. This must be a power of two
+    ///   no bigger than `len()`.
+    pub fn set_vectors(&mut self, hart: usize, mode: PrivMode, eiid: u32, count: usize) {
+        assert!(count.is_power_of_two() && count <= self.capable);
+        assert!(eiid as usize & (count - 1) == 0);
+        let addr = match mode {
+            PrivMode::Machine => imsic_m(hart),
+            PrivMode::Supervisor => imsic_s(hart),
+        };
+        let msgcontrol = self.msgcontrol().read();
+        // Disable MSI while we change the address and data.
+        self.msgcontrol().write(msgcontrol & !MSI_ENABLE);
+        self.set_message(addr, eiid);
+        // MME is log2 of the number of vectors
+        let mme = count.trailing_zeros() as u16;
+        self.msgcontrol().write((msgcontrol & !(7 << 4)) | mme << 4 | MSI_ENABLE);
+    }
+
+    /// # Overview
+    /// Point a single-vector MSI at a different hart without turning MSI
+    /// off, which could lose an interrupt. The vector must be masked so
+    /// the device never sends half of an update.
+    /// # Returns
+    /// `false` if more than one vector is enabled, since they all share
+    /// the address, or the function can't mask
+    fn retarget(&mut self, hart: usize, mode: PrivMode, eiid: u32) -> bool {
+        if self.enabled() != 1 || !self.can_mask() {
+            return false;
+        }
+        let addr = match mode {
+            PrivMode::Machine => imsic_m(hart),
+            PrivMode::Supervisor => imsic_s(hart),
+        };
+        self.set_message(addr, eiid);
+        true
+    }
+
+    /// Turn MSI off for this function.
+    pub fn disable(&mut self) {
+        self.msgcontrol().modify(|msgcontrol| msgcontrol & !MSI_ENABLE);
+    }
+
+    fn set_mask(&mut self, which: usize, masked: bool) -> bool {
+        assert!(which < self.capable);
+        match self.mask_bits() {
+            Some(bits) => {
+                bits.modify(|mask| {
+                    if masked {
+                        mask | 1 << which
+                    } else {
+                        mask & !(1 << which)
+                    }
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// # Overview
+    /// Mask a vector.
+    /// # Returns
+    /// `false` if this function doesn't support per-vector masking
+    pub fn mask(&mut self, which: usize) -> bool {
+        self.set_mask(which, true)
+    }
+
+    /// # Overview
+    /// Unmask a vector.
+    /// # Returns
+    /// `false` if this function doesn't support per-vector masking
+    pub fn unmask(&mut self, which: usize) -> bool {
+        self.set_mask(which, false)
+    }
+
+    /// # Overview
+    /// Check a vector's mask bit.
+    /// # Returns
+    /// `true` if the vector is masked. Without per-vector masking, no
+    /// vector ever is.
+    pub fn is_masked(&self, which: usize) -> bool {
+        assert!(which < self.capable);
+        self.mask_bits().is_some_and(|bits| bits.read() >> which & 1 == 1)
+    }
+
+    /// # Overview
+    /// Check a vector's pending bit.
+    /// # Returns
+    /// `true` if the vector is pending. Without per-vector masking there
+    /// are no pending bits, so this is always `false`.
+    pub fn is_pending(&self, which: usize) -> bool {
+        assert!(which < self.capable);
+        self.pending_bits().is_some_and(|bits| bits.read() >> which & 1 == 1)
+    }
+}
+
+/// A function's message signaled interrupts, either MSI or MSI-X.
+/// Drivers should go through this so they don't care which one the
+/// device has.
+#[derive(Clone, Copy)]
+pub enum MsiVectors {
+    Msi(Msi),
+    MsiX(MsixTable),
 }
 
+impl MsiVectors {
+    /// The maximum number of vectors the function has.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Msi(msi) => msi.len(),
+            Self::MsiX(msix) => msix.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// # Overview
+    /// Allocate EIIDs for as many vectors as we can and point them at a
+    /// hart's IMSIC. Every vector is left masked if the function can
+    /// mask it. MSI-X gets one EIID per vector from anywhere in the
+    /// interrupt file. MSI needs an aligned block of EIIDs, so it is
+    /// given the largest power of two that fits.
+    /// # Arguments
+    /// * `hart` - the hart to receive the messages
+    /// * `mode` - which of the hart's interrupt files (M or S) to use
+    /// * `eiids` - filled in with the EIID of each vector. Its length is the
+    ///   number of vectors wanted.
+    /// # Returns
+    /// The number of vectors set up. Vector `i` sends `eiids[i]`.
+    pub fn alloc(&mut self, hart: usize, mode: PrivMode, eiids: &mut [u32]) -> usize {
+        match self {
+            Self::MsiX(msix) => {
+                let count = eiids.len().min(msix.len());
+                for (i, slot) in eiids.iter_mut().enumerate().take(count) {
+                    match imsic_alloc(hart, mode) {
+                        Some(eiid) => {
+                            msix.mask(i);
+                            msix.set_vector(i, hart, mode, eiid);
+                            *slot = eiid;
+                        }
+                        None => return i,
+                    }
+                }
+                count
+            }
+            Self::Msi(msi) => {
+                let want = eiids.len().min(msi.len());
+                if want == 0 {
+                    return 0;
+                }
+                // Round down to a power of two, then keep halving until
+                // the IMSIC has a block that big.
+                let mut count = 1 << (usize::BITS - 1 - want.leading_zeros());
+                while count > 0 {
+                    if let Some(first) = imsic_alloc_block(hart, mode, count) {
+                        for (i, slot) in eiids.iter_mut().enumerate().take(count) {
+                            msi.mask(i);
+                            *slot = first + i as u32;
+                        }
+                        msi.set_vectors(hart, mode, first, count);
+                        return count;
+                    }
+                    count /= 2;
+                }
+                0
+            }
+        }
+    }
+
+    /// Mask a vector. Plain MSI without per-vector masking ignores this.
+    pub fn mask(&mut self, which: usize) {
+        match self {
+            Self::Msi(msi) => {
+                msi.mask(which);
+            }
+            Self::MsiX(msix) => msix.mask(which),
+        }
+    }
+
+    /// Unmask a vector.
+    pub fn unmask(&mut self, which: usize) {
+        match self {
+            Self::Msi(msi) => {
+                msi.unmask(which);
+            }
+            Self::MsiX(msix) => msix.unmask(which),
+        }
+    }
+
+    pub fn is_pending(&self, which: usize) -> bool {
+        match self {
+            Self::Msi(msi) => msi.is_pending(which),
+            Self::MsiX(msix) => msix.is_pending(which),
+        }
+    }
+
+    /// # Overview
+    /// Move a vector to another hart without losing a message. The vector
+    /// is given a new EIID on the new hart with the old one's handler, and
+    /// is masked while it moves. The device holds anything it wants to
+    /// send while masked in its pending bit and sends it when unmasked.
+    /// A message the device sent before the mask may still be on its way
+    /// to the old hart. Reading the vector back flushes it, since the
+    /// read's completion can't pass the device's earlier writes. If it
+    /// is then pending on the old hart, it is sent again to the new one.
+    /// Plain MSI can only do this with a single, maskable vector.
+    /// # Arguments
+    /// * `which` - the vector number
+    /// * `from` - the hart the vector goes to now
+    /// * `to` - the hart to move it to
+    /// * `mode` - which of the harts' interrupt files (M or S) it uses
+    /// * `eiid` - the EIID the vector sends now
+    /// # Returns
+    /// The EIID the vector now sends on `to`, or `None` if it could not
+    /// be moved, in which case it is left where it was
+    pub fn migrate(
+        &mut self,
+        which: usize,
+        from: usize,
+        to: usize,
+        mode: PrivMode,
+        eiid: u32,
+    ) -> Option<u32> {
+        if let Self::Msi(msi) = self {
+            if msi.enabled() != 1 || !msi.can_mask() {
+                println!("MSI vector {} shares its address, so it can't move alone.", which);
+                return None;
+            }
+        }
+        let new = imsic_alloc(to, mode)?;
+        if !imsic_copy_handler(from, to, mode, eiid, new) {
+            imsic_free(to, mode, new);
+            return None;
+        }
+        let masked = match self {
+            Self::Msi(msi) => msi.is_masked(which),
+            Self::MsiX(msix) => msix.is_masked(which),
+        };
+        self.mask(which);
+        match self {
+            Self::Msi(msi) => {
+                // The mask bit read is the flush.
+                msi.is_masked(which);
+                msi.retarget(to, mode, new);
+            }
+            // set_vector reads the vector before it writes it.
+            Self::MsiX(msix) => msix.set_vector(which, to, mode, new),
+        }
+        if imsic_take_pending(from, mode, eiid) {
+            imsic_send(to, mode, new);
+        }
+        if !masked {
+            self.unmask(which);
+        }
+        imsic_disable_on(from, mode, eiid);
+        imsic_free(from, mode, eiid);
+        Some(new)
+    }
+}
+
+// PCI Express extended capabilities start here in configuration space.
+const EXT_CAP_START: usize = 0x100;
+
+// Extended capability IDs
+pub const EXT_CAP_AER: u16 = 0x0001;
+pub const EXT_CAP_DSN: u16 = 0x0003;
+pub const EXT_CAP_ACS: u16 = 0x000d;
+pub const EXT_CAP_SRIOV: u16 = 0x0010;
+
+// The most extended capabilities we remember for one function
+pub const MAX_PCI_EXT_CAPS: usize = 16;
+
+/// A capability in a function's extended capability list.
+#[derive(Clone, Copy, Default)]
+pub struct PciExtCap {
+    pub id: u16,
+    pub version: u8,
+    // Offset into configuration space
+    pub offset: u16,
+}
+
+/// The PCI Express capability (ID 0x10). The slot registers are only
+/// there on ports with a slot and the root registers only on root ports.
+#[repr(C)]
+pub struct PcieCapability {
+    pub cap: Capability,
+    pub caps: Volatile<u16>,
+    pub device_caps: Volatile<u32>,
+    pub device_control: Volatile<u16>,
+    pub device_status: Volatile<u16>,
+    pub link_caps: Volatile<u32>,
+    pub link_control: Volatile<u16>,
+    pub link_status: Volatile<u16>,
+    pub slot_caps: Volatile<u32>,
+    pub slot_control: Volatile<u16>,
+    pub slot_status: Volatile<u16>,
+    pub root_control: Volatile<u16>,
+    pub root_caps: Volatile<u16>,
+    pub root_status: Volatile<u32>,
+}
+
+/// The header every extended capability starts with: the ID in bits
+/// 15:0, the version in 19:16 and the next pointer in 31:20.
+#[repr(C)]
+struct ExtCapability {
+    pub header: Volatile<u32>,
+}
+
+/// Advanced Error Reporting (extended capability 0x0001). The root
+/// registers at the end are only there on root ports.
+#[repr(C)]
+pub struct AerCapability {
+    pub header: Volatile<u32>,
+    pub uncorrectable_status: Volatile<u32>,
+    pub uncorrectable_mask: Volatile<u32>,
+    pub uncorrectable_severity: Volatile<u32>,
+    pub correctable_status: Volatile<u32>,
+    pub correctable_mask: Volatile<u32>,
+    pub control: Volatile<u32>,
+    pub header_log: [Volatile<u32>; 4],
+    pub root_command: Volatile<u32>,
+    pub root_status: Volatile<u32>,
+    pub error_source_id: Volatile<u32>,
+}
+
+// The uncorrectable error status bits and what they mean
+pub const AER_UNCORRECTABLE: [(u32, &str); 17] = [
+    (1 << 4, "Data Link Protocol Error"),
+    (1 << 5, "Surprise Down"),
+    (1 << 12, "Poisoned TLP"),
+    (1 << 13, "Flow Control Protocol Error"),
+    (1 << 14, "Completion Timeout"),
+    (1 << 15, "Completer Abort"),
+    (1 << 16, "Unexpected Completion"),
+    (1 << 17, "Receiver Overflow"),
+    (1 << 18, "Malformed TLP"),
+    (1 << 19, "ECRC Error"),
+    (1 << 20, "Unsupported Request"),
+    (1 << 21, "ACS Violation"),
+    (1 << 22, "Uncorrectable Internal Error"),
+    (1 << 23, "MC Blocked TLP"),
+    (1 << 24, "AtomicOp Egress Blocked"),
+    (1 << 25, "TLP Prefix Blocked"),
+    (1 << 26, "Poisoned TLP Egress Blocked"),
+];
+
+// The correctable error status bits and what they mean
+pub const AER_CORRECTABLE: [(u32, &str); 8] = [
+    (1 << 0, "Receiver Error"),
+    (1 << 6, "Bad TLP"),
+    (1 << 7, "Bad DLLP"),
+    (1 << 8, "REPLAY_NUM Rollover"),
+    (1 << 12, "Replay Timer Timeout"),
+    (1 << 13, "Advisory Non-Fatal Error"),
+    (1 << 14, "Corrected Internal Error"),
+    (1 << 15, "Header Log Overflow"),
+];
+
+impl AerCapability {
+    pub fn uncorrectable(&self) -> u32 {
+        self.uncorrectable_status.read()
+    }
+
+    pub fn correctable(&self) -> u32 {
+        self.correctable_status.read()
+    }
+
+    /// The TLP header of the first uncorrectable error that was logged.
+    pub fn header(&self) -> [u32; 4] {
+        let mut log = [0; 4];
+        for (i, dw) in log.iter_mut().enumerate() {
+            *dw = self.header_log[i].read();
+        }
+        log
+    }
+
+    /// # Overview
+    /// Clear error status bits. Both registers are write 1 to clear.
+    pub fn clear(&self, uncorrectable: u32, correctable: u32) {
+        self.uncorrectable_status.write(uncorrectable);
+        self.correctable_status.write(correctable);
+    }
+
+    /// # Overview
+    /// Print the name of every error set in the status registers.
+    pub fn print_status(&self) {
+        let (unc, cor) = (self.uncorrectable(), self.correctable());
+        let severity = self.uncorrectable_severity.read();
+        println!("    AER uncorrectable 0x{:08x}, correctable 0x{:08x}", unc, cor);
+        for (bit, name) in AER_UNCORRECTABLE.iter().filter(|(bit, _)| unc & bit != 0) {
+            let fatal = if severity & bit != 0 { "fatal" } else { "non-fatal" };
+            println!("      {} ({})", name, fatal);
+        }
+        for (_, name) in AER_CORRECTABLE.iter().filter(|(bit, _)| cor & bit != 0) {
+            println!("      {} (correctable)", name);
+        }
+    }
+}
+
+/// Device Serial Number (extended capability 0x0003).
+#[repr(C)]
+pub struct DsnCapability {
+    pub header: Volatile<u32>,
+    pub serial_lo: Volatile<u32>,
+    pub serial_hi: Volatile<u32>,
+}
+
+impl DsnCapability {
+    /// The 64-bit serial number, which is an EUI-64.
+    pub fn serial(&self) -> u64 {
+        (self.serial_hi.read() as u64) << 32 | self.serial_lo.read() as u64
+    }
+}
+
+/// Access Control Services (extended capability 0x000d). The egress
+/// control vector that may follow is not decoded.
 #[repr(C)]
-#[allow(dead_code)]
-struct MsixPba {
-    pub pending: u64,
+pub struct AcsCapability {
+    pub header: Volatile<u32>,
+    pub capability: Volatile<u16>,
+    pub control: Volatile<u16>,
+}
+
+// The ACS capability/control bits, by their spec abbreviations
+pub const ACS_BITS: [(u16, &str); 7] = [
+    (1 << 0, "SrcValid"),
+    (1 << 1, "TransBlk"),
+    (1 << 2, "ReqRedir"),
+    (1 << 3, "CmpltRedir"),
+    (1 << 4, "UpstreamFwd"),
+    (1 << 5, "EgressCtrl"),
+    (1 << 6, "DirectTrans"),
+];
+
+/// Single Root I/O Virtualization (extended capability 0x0010).
+#[repr(C)]
+pub struct SriovCapability {
+    pub header: Volatile<u32>,
+    pub capabilities: Volatile<u32>,
+    pub control: Volatile<u16>,
+    pub status: Volatile<u16>,
+    pub initial_vfs: Volatile<u16>,
+    pub total_vfs: Volatile<u16>,
+    pub num_vfs: Volatile<u16>,
+    pub function_dependency_link: Volatile<u8>,
+    pub reserved0: Volatile<u8>,
+    pub first_vf_offset: Volatile<u16>,
+    pub vf_stride: Volatile<u16>,
+    pub reserved1: Volatile<u16>,
+    pub vf_device_id: Volatile<u16>,
+    pub supported_page_sizes: Volatile<u32>,
+    pub system_page_size: Volatile<u32>,
+    pub vf_bar: [Volatile<u32>; 6],
+    pub migration_state_array: Volatile<u32>,
+}
+
+/// The name of an extended capability.
+fn ext_cap_name(id: u16) -> &'static str {
+    match id {
+        EXT_CAP_AER => "Advanced Error Reporting",
+        0x0002 => "Virtual Channel",
+        EXT_CAP_DSN => "Device Serial Number",
+        0x000b => "Vendor Specific",
+        EXT_CAP_ACS => "Access Control Services",
+        0x000e => "Alternative Routing-ID",
+        EXT_CAP_SRIOV => "SR-IOV",
+        0x0019 => "Secondary PCI Express",
+        _ => "Unknown",
+    }
+}
+
+/// # Overview
+/// Print one extended capability, decoding the ones we have structures
+/// for.
+/// # Arguments
+/// * `cfg` - the start of the function's configuration space
+/// * `cap` - the capability
+fn print_ext_cap(cfg: *const u8, cap: &PciExtCap) {
+    let ptr = unsafe { cfg.add(cap.offset as usize) };
+    println!("    [{:03x}] {} v{}", cap.offset, ext_cap_name(cap.id), cap.version);
+    match cap.id {
+        EXT_CAP_AER => unsafe { &*(ptr as *const AerCapability) }.print_status(),
+        EXT_CAP_DSN => {
+            let dsn = unsafe { &*(ptr as *const DsnCapability) };
+            let serial = dsn.serial().to_be_bytes();
+            print!("    Serial number:");
+            for (i, byte) in serial.iter().enumerate() {
+                print!("{}{:02x}", if i == 0 { " " } else { "-" }, byte);
+            }
+            println!();
+        }
+        EXT_CAP_ACS => {
+            let acs = unsafe { &*(ptr as *const AcsCapability) };
+            let capability = acs.capability.read();
+            let control = acs.control.read();
+            print!("    ACS:");
+            for (bit, name) in ACS_BITS.iter().filter(|(bit, _)| capability & bit != 0) {
+                print!(" {}{}", name, if control & bit != 0 { "+" } else { "-" });
+            }
+            println!();
+        }
+        EXT_CAP_SRIOV => {
+            let sriov = unsafe { &*(ptr as *const SriovCapability) };
+            println!(
+                "    SR-IOV: {} of {} VFs (initial {}), offset {}, stride {}, VF device {:04x}",
+                sriov.num_vfs.read(),
+                sriov.total_vfs.read(),
+                sriov.initial_vfs.read(),
+                sriov.first_vf_offset.read(),
+                sriov.vf_stride.read(),
+                sriov.vf_device_id.read()
+            );
+        }
+        _ => {}
+    }
+}
+
+/// # Overview
+/// Scan every slot on a bus. Bridges are given bus numbers as they are
+/// found, so this goes depth first.
+/// # Arguments
+/// * `next_bus` - the next bus number to give to a bridge
+/// * `bus` - the bus number to scan
+/// * `parent` - the bridge (index in PCI_FUNCTIONS) the bus is behind
+fn pci_scan_bus(next_bus: &mut usize, bus: usize, parent: Option<usize>) {
+    // Slot 0 on the root bus is the host bridge, which we leave alone.
+    let slot_start = if bus == 0 { 1 } else { 0 };
+    for slot in slot_start..32 {
+        let ecam = Ecam::as_ref(bus, slot, 0);
+        if ecam.vendor_id.read() == 0xffff {
+            // Vendor id 0xFFFF means "not connected"
+            continue;
+        }
+        // Only look at functions 1-7 if function 0 says they're there.
+        let funcs = if ecam.header_type.read() & HEADER_TYPE_MULTIFUNCTION != 0 { 8 } else { 1 };
+        for func in 0..funcs {
+            pci_scan_function(next_bus, bus, slot, func, parent);
+        }
+    }
 }
 
-fn pci_setup(bus: usize, slot: usize) {
-    let ecam = Ecam::as_mut(bus, slot);
-    if ecam.vendor_id == 0xffff {
-        // Vendor id 0xFFFF means "not connected"
+fn pci_scan_function(
+    next_bus: &mut usize,
+    bus: usize,
+    slot: usize,
+    func: usize,
+    parent: Option<usize>,
+) {
+    let ecam = Ecam::as_ref(bus, slot, func);
+    if ecam.vendor_id.read() == 0xffff {
         return;
     }
-    match ecam.header_type {
-        0 => pci_setup_type0(bus, slot, ecam),
-        1 => pci_setup_type1(bus, slot, ecam),
-        _ => panic!("Unknown PCI type {}.", ecam.header_type),
+    let found = unsafe { (*addr_of!(PCI_FUNCTIONS)).iter().flatten() }
+        .any(|f| f.bus as usize == bus && f.slot as usize == slot && f.func as usize == func);
+    if found {
+        // Already enumerated, which happens when a hot-plug port rescans.
+        return;
+    }
+    let header_type = ecam.header_type.read() & !HEADER_TYPE_MULTIFUNCTION;
+    let num_bars = match header_type {
+        0 => 6,
+        1 => 2,
+        _ => {
+            println!("Unknown PCI type {} at {:02x}:{:02x}.{}.", header_type, bus, slot, func);
+            return;
+        }
+    };
+    // Turn off decoding while we size the BARs.
+    ecam.command_reg.write(0);
+    let mut bars = [PciBar::default(); 6];
+    probe_bars(ecam, &mut bars[..num_bars]);
+    let index = match pci_add_function(PciFunction {
+        bus: bus as u8,
+        slot: slot as u8,
+        func: func as u8,
+        vendor_id: ecam.vendor_id.read(),
+        device_id: ecam.device_id.read(),
+        header_type,
+        parent,
+        secondary_bus: 0,
+        subordinate_bus: 0,
+        bars,
+        mem: PciWindow::default(),
+        pref: PciWindow::default(),
+        mem_align: BRIDGE_WINDOW_ALIGN,
+        pref_align: BRIDGE_WINDOW_ALIGN,
+        pref64: false,
+        hotplug: false,
+    }) {
+        Some(index) => index,
+        None => return,
+    };
+    if header_type == 1 {
+        pci_scan_bridge(next_bus, bus, ecam, index);
     }
 }
 
-fn pci_setup_type0(bus: usize, slot: usize, ecam: &mut Ecam) {
-    // Type 0 setup (devices)
-    let mut baraddr = PCI_BAR_BASE | (bus << 20) | (slot << 16);
-    ecam.command_reg = 0;
+/// # Overview
+/// Find the size and type of every BAR. This leaves the BARs
+/// unassigned. Decoding must be off.
+fn probe_bars(ecam: &Ecam, bars: &mut [PciBar]) {
+    // Type 1 headers only have the first two BARs, which are in the same
+    // place as a type 0 header's.
+    let regs = &ecam.type0().bar;
     let mut i = 0;
-    while i < 6 {
-        unsafe {
-            let barval = ecam.typex.type0.bar[i];
-            if barval == 0 || barval & 1 != 0 {
-                // If the bar is all 0s, it is unimplemented
-                // If the first bit is not 0, then it is I/O space,
-                // which we don't support.
-                i += 1;
-                continue;
+    while i < bars.len() {
+        let barval = regs[i].read();
+        if barval & BAR_IO != 0 {
+            // I/O space, which we don't support.
+            i += 1;
+            continue;
+        }
+        let is64 = barval & BAR_TYPE_64 != 0 && i + 1 < bars.len();
+        regs[i].write(0xFFFF_FFFF);
+        let mut mask = (regs[i].read() & !0xF) as u64;
+        if is64 {
+            regs[i + 1].write(0xFFFF_FFFF);
+            mask |= (regs[i + 1].read() as u64) << 32;
+            regs[i + 1].write(0);
+        } else {
+            // Pretend the upper half is all 1s so the size comes out right.
+            mask |= 0xFFFF_FFFF_0000_0000;
+        }
+        regs[i].write(0);
+        if mask as u32 != 0 {
+            // If the size bits are all 0s, the BAR is unimplemented.
+            bars[i] = PciBar {
+                size: !mask + 1,
+                addr: None,
+                is64,
+                prefetchable: barval & BAR_PREFETCHABLE != 0,
+            };
+        }
+        i += if is64 { 2 } else { 1 };
+    }
+}
+
+/// # Overview
+/// Give a bridge the next bus number and enumerate everything behind it.
+/// The bridge's memory windows are sized and programmed later, once
+/// we know everything behind it. A bridge we have no bus number for is
+/// left without one, and pci_enable_function leaves it turned off.
+/// # Arguments
+/// * `bus` - the bus the bridge is on
+/// * `ecam` - the bridge's configuration space
+/// * `index` - the bridge's index in PCI_FUNCTIONS
+fn pci_scan_bridge(next_bus: &mut usize, bus: usize, ecam: &Ecam, index: usize) {
+    let secondary = *next_bus;
+    if secondary > 255 {
+        let func = pci_function(index);
+        println!(
+            "Out of PCI bus numbers for the bridge at {:02x}:{:02x}.{}.",
+            func.bus, func.slot, func.func
+        );
+        ecam.type1().secondary_bus_no.write(0);
+        ecam.type1().subordinate_bus_no.write(0);
+        return;
+    }
+    *next_bus += 1;
+
+    // Until we know how many buses are behind this bridge, let it
+    // forward configuration cycles for every bus after this one.
+    ecam.type1().primary_bus_no.write(bus as u8);
+    ecam.type1().secondary_bus_no.write(secondary as u8);
+    ecam.type1().subordinate_bus_no.write(0xFF);
+
+    pci_scan_bus(next_bus, secondary, Some(index));
+    let subordinate = *next_bus - 1;
+    ecam.type1().subordinate_bus_no.write(subordinate as u8);
+
+    let func = pci_function(index);
+    func.secondary_bus = secondary as u8;
+    func.subordinate_bus = subordinate as u8;
+    func.pref64 = ecam.type1().prefetch_memory_base.read() & 0xF == BRIDGE_PREF_64;
+    func.hotplug = ecam_find_cap(ecam, CAP_PCIE).is_some_and(|offset| {
+        let pcie = ecam.at::<PcieCapability>(offset);
+        pcie.caps.read() & PCIE_CAPS_SLOT != 0 && pcie.slot_caps.read() & PCIE_SLOT_CAPS_HPC != 0
+    });
+    // We don't know what will be plugged in, so keep its prefetchable
+    // window where anything can use it.
+    if func.hotplug {
+        func.pref64 = false;
+    }
+}
+
+/// # Overview
+/// Find a capability by walking the list in configuration space. This is
+/// for before there is a PciDevice to look in.
+/// # Returns
+/// The capability's offset in configuration space
+fn ecam_find_cap(ecam: &Ecam, id: u8) -> Option<usize> {
+    if ecam.status_reg.read() >> 4 & 1 != 1 {
+        return None;
+    }
+    let mut c = ecam.type0().capes_pointer.read() as usize;
+    // Guard against a broken list that loops forever.
+    for _ in 0..48 {
+        if c == 0 {
+            break;
+        }
+        let cap = ecam.at::<Capability>(c);
+        if cap.id.read() == id {
+            return Some(c);
+        }
+        c = cap.next.read() as usize;
+    }
+    None
+}
+
+/// # Overview
+/// Collect the MMIO space wanted by every function directly behind
+/// `parent`: each function's BARs and each bridge's windows. They come
+/// back sorted largest alignment first, so packing them in order never
+/// wastes space on padding.
+/// # Arguments
+/// * `parent` - the bridge (index in PCI_FUNCTIONS), or None for the root bus
+/// * `include` - which functions (by index in PCI_FUNCTIONS) to collect
+/// * `out` - where to put the resources
+/// # Returns
+/// The number of resources put into `out`
+fn pci_collect(
+    parent: Option<usize>,
+    include: &dyn Fn(usize) -> bool,
+    out: &mut [Resource],
+) -> usize {
+    let mut count = 0;
+    let mut push = |res: Resource| {
+        if count < out.len() {
+            out[count] = res;
+            count += 1;
+        } else {
+            println!("Too many PCI resources behind one bus.");
+        }
+    };
+    for (index, func) in unsafe { (*addr_of!(PCI_FUNCTIONS)).iter().enumerate() } {
+        let func = match func {
+            Some(func) if func.parent == parent && include(index) => func,
+            _ => continue,
+        };
+        for (bar, b) in func.bars.iter().enumerate().filter(|(_, b)| b.size != 0) {
+            push(Resource {
+                kind: b.kind(),
+                size: b.size,
+                align: b.size,
+                func: index,
+                bar,
+            });
+        }
+        if func.header_type == 1 {
+            if func.mem.size != 0 {
+                push(Resource {
+                    kind: ResourceKind::Mem,
+                    size: func.mem.size,
+                    align: func.mem_align,
+                    func: index,
+                    bar: RESOURCE_MEM_WINDOW,
+                });
+            }
+            if func.pref.size != 0 {
+                push(Resource {
+                    kind: if func.pref64 { ResourceKind::Pref64 } else { ResourceKind::Pref32 },
+                    size: func.pref.size,
+                    align: func.pref_align,
+                    func: index,
+                    bar: RESOURCE_PREF_WINDOW,
+                });
             }
-            let bartype = barval >> 1 & 3;
-            match bartype {
-                0b00 => {
-                    // 32-bit BAR
-                    let barptr = &mut ecam.typex.type0.bar[i] as *mut u32;
-                    barptr.write_volatile(0xFFFF_FFFF);
-                    let barsize = !(barptr.read_volatile() & !0xF) + 1;
-                    // println!("  32-bit BAR {}, size {} bytes set to 0x{:08x}.", i, barsize, baraddr);
-                    barptr.write_volatile(baraddr as u32);
-                    baraddr += barsize as usize;
-                    i += 1;
+        }
+    }
+    out[..count].sort_unstable_by_key(|r| core::cmp::Reverse(r.align));
+    count
+}
+
+/// # Overview
+/// Work out how big each bridge's windows must be and how they must be
+/// aligned. PCI_FUNCTIONS is in depth-first order, so walking it
+/// backwards sizes every bridge after the bridges behind it.
+fn pci_size_bridges() {
+    let mut res = [Resource {
+        kind: ResourceKind::Mem,
+        size: 0,
+        align: 1,
+        func: 0,
+        bar: 0,
+    }; MAX_BUS_RESOURCES];
+    for index in (0..MAX_PCI_FUNCTIONS).rev() {
+        match unsafe { PCI_FUNCTIONS[index].as_ref() } {
+            Some(func) if func.header_type == 1 => {}
+            _ => continue,
+        }
+        let count = pci_collect(Some(index), &|_| true, &mut res);
+        let mut mem = PciWindow::new(0, u64::MAX);
+        let mut pref = PciWindow::new(0, u64::MAX);
+        // The prefetchable window can only go above 4 GiB if the
+        // bridge supports it and everything behind it can be there.
+        let mut pref64 = pci_function(index).pref64;
+        let (mut mem_align, mut pref_align) = (BRIDGE_WINDOW_ALIGN, BRIDGE_WINDOW_ALIGN);
+        for r in res[..count].iter() {
+            match r.kind {
+                ResourceKind::Mem => {
+                    mem.alloc(r.size, r.align);
+                    mem_align = mem_align.max(r.align);
+                }
+                kind => {
+                    pref64 &= kind == ResourceKind::Pref64;
+                    pref.alloc(r.size, r.align);
+                    pref_align = pref_align.max(r.align);
                 }
-                0b10 => {
-                    // 64-bit BAR
-                    let barptr = &mut ecam.typex.type0.bar[i] as *mut u32 as *mut u64;
-                    barptr.write_volatile(0xFFFF_FFFF_FFFF_FFFF);
-                    let barsize = !(barptr.read_volatile() & !0xF) + 1;
-                    // println!("  64-bit BAR {}, size {} bytes set to 0x{:08x}.", i, barsize, baraddr);
-                    barptr.write_volatile(baraddr as u64);
-                    baraddr += barsize as usize;
-                    i += 2;
+            }
+        }
+        let round = |size: u64| (size + BRIDGE_WINDOW_ALIGN - 1) & !(BRIDGE_WINDOW_ALIGN - 1);
+        let func = pci_function(index);
+        let (mut mem, mut pref) = (round(mem.used()), round(pref.used()));
+        if func.hotplug {
+            mem = mem.max(HOTPLUG_MEM_SIZE);
+            pref = pref.max(HOTPLUG_PREF_SIZE);
+        }
+        func.mem = PciWindow::new(0, mem);
+        func.pref = PciWindow::new(0, pref);
+        func.mem_align = mem_align;
+        func.pref_align = pref_align;
+        func.pref64 = pref64;
+    }
+}
+
+/// # Overview
+/// Hand out MMIO space to the functions directly behind `parent` from the
+/// given windows, and program the BARs and bridge windows.
+/// # Arguments
+/// * `parent` - the bridge (index in PCI_FUNCTIONS), or None for the root bus
+/// * `include` - which functions (by index in PCI_FUNCTIONS) to assign
+/// * `mem` - the non-prefetchable window
+/// * `pref` - the prefetchable window below 4 GiB
+/// * `pref64` - the prefetchable window that may be above 4 GiB, if any
+fn pci_assign(
+    parent: Option<usize>,
+    include: &dyn Fn(usize) -> bool,
+    mem: &mut PciWindow,
+    pref: &mut PciWindow,
+    mut pref64: Option<&mut PciWindow>,
+) {
+    let mut res = [Resource {
+        kind: ResourceKind::Mem,
+        size: 0,
+        align: 1,
+        func: 0,
+        bar: 0,
+    }; MAX_BUS_RESOURCES];
+    let count = pci_collect(parent, include, &mut res);
+    for r in res[..count].iter() {
+        let addr = match (r.kind, pref64.as_deref_mut()) {
+            (ResourceKind::Mem, _) => mem.alloc(r.size, r.align),
+            (ResourceKind::Pref64, Some(window)) => window.alloc(r.size, r.align),
+            _ => pref.alloc(r.size, r.align),
+        };
+        let func = pci_function(r.func);
+        let ecam = Ecam::as_ref(func.bus as usize, func.slot as usize, func.func as usize);
+        let addr = match addr {
+            Some(addr) => addr,
+            None => {
+                println!(
+                    "No room for {} bytes of PCI memory for {:02x}:{:02x}.{} {}.",
+                    r.size,
+                    func.bus,
+                    func.slot,
+                    func.func,
+                    match r.bar {
+                        RESOURCE_MEM_WINDOW => "memory window",
+                        RESOURCE_PREF_WINDOW => "prefetchable window",
+                        _ => "BAR",
+                    }
+                );
+                // Leave it unassigned. pci_enable won't turn on decoding.
+                continue;
+            }
+        };
+        match r.bar {
+            RESOURCE_MEM_WINDOW => {
+                func.mem = PciWindow::new(addr, r.size);
+                let end = addr + r.size - 1;
+                ecam.type1().memory_base.write((addr >> 16) as u16);
+                ecam.type1().memory_limit.write((end >> 16) as u16);
+            }
+            RESOURCE_PREF_WINDOW => {
+                func.pref = PciWindow::new(addr, r.size);
+                let end = addr + r.size - 1;
+                ecam.type1().prefetch_memory_base.write((addr >> 16) as u16);
+                ecam.type1().prefetch_memory_limit.write((end >> 16) as u16);
+                ecam.type1().prefetch_base_upper.write((addr >> 32) as u32);
+                ecam.type1().prefetch_limit_upper.write((end >> 32) as u32);
+            }
+            bar => {
+                func.bars[bar].addr = Some(addr);
+                ecam.type0().bar[bar].write(addr as u32);
+                if func.bars[bar].is64 {
+                    ecam.type0().bar[bar + 1].write((addr >> 32) as u32);
                 }
-                _ => panic!("invalid bar type {}", bartype),
             }
         }
     }
+}
+
+/// # Overview
+/// Assign MMIO space to every function. The root bus gets the windows
+/// the host bridge decodes, and each bridge then hands its own windows
+/// to whatever is behind it. PCI_FUNCTIONS is in depth-first order, so
+/// every bridge has its windows before we get to its children.
+fn pci_assign_all() {
+    let mut mem = PciWindow::new(PCI_BAR_BASE, PCI_BAR_SIZE / 2);
+    let mut pref = PciWindow::new(PCI_PREF_BASE, PCI_BAR_SIZE / 2);
+    #[cfg(target_pointer_width = "64")]
+    let mut pref64 = PciWindow::new(PCI_BAR64_BASE, PCI_BAR64_SIZE);
+    #[cfg(target_pointer_width = "64")]
+    pci_assign(None, &|_| true, &mut mem, &mut pref, Some(&mut pref64));
+    #[cfg(not(target_pointer_width = "64"))]
+    pci_assign(None, &|_| true, &mut mem, &mut pref, None);
+
+    for (index, func) in unsafe { (*addr_of!(PCI_FUNCTIONS)).iter().enumerate() } {
+        let func = match func {
+            Some(func) if func.header_type == 1 => *func,
+            _ => continue,
+        };
+        let ecam = Ecam::as_ref(func.bus as usize, func.slot as usize, func.func as usize);
+        // A window that didn't get space (or isn't needed) is turned off
+        // by putting its base above its limit.
+        let mut mem = func.mem;
+        if mem.size == 0 || mem.base == 0 {
+            mem = PciWindow::default();
+            ecam.type1().memory_base.write(0xFFF0);
+            ecam.type1().memory_limit.write(0);
+        }
+        let mut pref = func.pref;
+        if pref.size == 0 || pref.base == 0 {
+            pref = PciWindow::default();
+            ecam.type1().prefetch_memory_base.write(0xFFF0);
+            ecam.type1().prefetch_memory_limit.write(0);
+            ecam.type1().prefetch_base_upper.write(0);
+            ecam.type1().prefetch_limit_upper.write(0);
+        }
+        // A bridge only has one prefetchable window, and it is only
+        // 64-bit if everything behind it is.
+        if func.pref64 {
+            let mut none = PciWindow::default();
+            pci_assign(Some(index), &|_| true, &mut mem, &mut none, Some(&mut pref));
+        } else {
+            pci_assign(Some(index), &|_| true, &mut mem, &mut pref, None);
+        }
+    }
+}
+
+/// # Overview
+/// Turn on every function we found, now that it has its MMIO space, and
+/// add it as a device for the drivers to find. Functions whose BARs didn't
+/// all fit are left with decoding off.
+fn pci_enable_all() {
+    for index in (0..MAX_PCI_FUNCTIONS).filter(|&i| unsafe { PCI_FUNCTIONS[i].is_some() }) {
+        pci_enable_function(index);
+    }
+}
 
-    ecam.command_reg = COMMAND_REG_BUS_MASTER | COMMAND_REG_MEM_SPACE;
-    enum_caps(ecam);
-    if ecam.device_id == 0x0010 {
-        pci_add_device(PciDevice::Nvme(get_bar_addr(ecam, 0)));
+/// # Overview
+/// Turn on one function and add it as a device.
+/// # Arguments
+/// * `index` - the function's index in PCI_FUNCTIONS
+fn pci_enable_function(index: usize) {
+    let func = *pci_function(index);
+    let ecam = Ecam::as_ref(func.bus as usize, func.slot as usize, func.func as usize);
+    if func.bars.iter().any(|b| b.size != 0 && b.addr.is_none()) {
+        println!(
+            "Leaving {:02x}:{:02x}.{} disabled since its BARs don't fit.",
+            func.bus, func.slot, func.func
+        );
+        return;
+    }
+    if func.header_type == 1 && func.secondary_bus == 0 {
+        println!(
+            "Leaving {:02x}:{:02x}.{} disabled since it has no bus numbers.",
+            func.bus, func.slot, func.func
+        );
+        return;
+    }
+    // Bus master has to be on for a bridge to forward DMA and MSIs
+    // from its children upstream.
+    ecam.command_reg.write(COMMAND_REG_BUS_MASTER | COMMAND_REG_MEM_SPACE);
+    println!(
+        "PCI {:02x}:{:02x}.{} {:04x}:{:04x} {}",
+        func.bus,
+        func.slot,
+        func.func,
+        func.vendor_id,
+        func.device_id,
+        class_name(ecam.class_basecode.read(), ecam.class_subcode.read())
+    );
+    let mut dev = PciDevice {
+        bus: func.bus,
+        slot: func.slot,
+        func: func.func,
+        vendor_id: func.vendor_id,
+        device_id: func.device_id,
+        class: (ecam.class_basecode.read() as u32) << 16
+            | (ecam.class_subcode.read() as u32) << 8
+            | ecam.prog_if.read() as u32,
+        revision: ecam.revision_id.read(),
+        header_type: func.header_type,
+        function: index,
+        bars: func.bars,
+        msi: None,
+        intx: pci_intx_irq(index),
+        caps: [PciCap::default(); MAX_PCI_CAPS],
+        num_caps: 0,
+        ext_caps: [PciExtCap::default(); MAX_PCI_EXT_CAPS],
+        num_ext_caps: 0,
+        driver: None,
+        driver_data: 0,
+    };
+    if let Some(irq) = dev.intx {
+        ecam.type0().interrupt_line.write(irq as u8);
     }
+    enum_caps(ecam, &mut dev);
+    enum_ext_caps(ecam, &mut dev);
+    pci_add_device(dev);
 }
 
-fn pci_setup_type1(bus: usize, slot: usize, ecam: &mut Ecam) {
-    // Type 1 setup (bridges)
+/// # Overview
+/// Enumerate whatever is behind a bridge that isn't known yet, give it
+/// space from the bridge's windows and bind drivers. This is for hot-plug
+/// ports, which have no spare bus numbers, so a bridge plugged in behind
+/// one is found but nothing behind it is.
+/// # Arguments
+/// * `index` - the bridge's index in PCI_FUNCTIONS
+/// # Returns
+/// The number of functions found
+pub fn pci_rescan_bridge(index: usize) -> usize {
+    let bridge = *pci_function(index);
+    // Remember what we already know about. Those functions may be bound
+    // to drivers, so their space must not move.
+    let mut known = [false; MAX_PCI_FUNCTIONS];
+    for (i, func) in unsafe { (*addr_of!(PCI_FUNCTIONS)).iter().enumerate() } {
+        known[i] = func.is_some();
+    }
+    // Anything bigger than 255 means there are no bus numbers left.
+    let mut next_bus = 256;
+    pci_scan_bus(&mut next_bus, bridge.secondary_bus as usize, Some(index));
+
+    let is_child = |i: usize| unsafe { PCI_FUNCTIONS[i] }.is_some_and(|f| f.parent == Some(index));
+    let is_new = |i: usize| !known[i] && is_child(i);
 
-    // To make things easy, the bridge is encoded with the bus number
-    // which is the same as the slot of the bridge.
-    let addrst = PCI_BAR_BASE | (slot << 20);
-    let addred = addrst + ((1 << 20) - 1);
+    // Hand out space from the bridge's windows, which are only ours to use
+    // if they were turned on, after whatever the other children use.
+    let usable = |w: PciWindow| match w.size != 0 && w.base != 0 {
+        true => PciWindow::new(w.base, w.size),
+        false => PciWindow::default(),
+    };
+    let (mut mem, mut pref) = (usable(bridge.mem), usable(bridge.pref));
+    for i in (0..MAX_PCI_FUNCTIONS).filter(|&i| known[i] && is_child(i)) {
+        let func = pci_function(i);
+        for b in func.bars.iter().filter(|b| b.size != 0) {
+            if let Some(addr) = b.addr {
+                match b.kind() {
+                    ResourceKind::Mem => mem.reserve(addr, b.size),
+                    _ => pref.reserve(addr, b.size),
+                }
+            }
+        }
+        if func.header_type == 1 {
+            mem.reserve(func.mem.base, func.mem.size);
+            pref.reserve(func.pref.base, func.pref.size);
+        }
+    }
+    pci_assign(Some(index), &is_new, &mut mem, &mut pref, None);
 
-    ecam.command_reg = COMMAND_REG_MEM_SPACE;
-    ecam.typex.type1.memory_base = (addrst >> 16) as u16;
-    ecam.typex.type1.memory_limit = (addred >> 16) as u16;
-    ecam.typex.type1.prefetch_memory_base = (addrst >> 16) as u16;
-    ecam.typex.type1.prefetch_memory_limit = (addred >> 16) as u16;
-    ecam.typex.type1.primary_bus_no = bus as u8;
-    ecam.typex.type1.secondary_bus_no = slot as u8;
-    ecam.typex.type1.subordinate_bus_no = slot as u8;
+    let mut found = 0;
+    for i in (0..MAX_PCI_FUNCTIONS).filter(|&i| is_new(i)) {
+        pci_enable_function(i);
+        found += 1;
+    }
+    found
 }
 
-fn enum_caps(ecam: &Ecam) {
+/// # Overview
+/// Unbind and forget everything behind a bridge, such as when a device is
+/// unplugged from a hot-plug port.
+/// # Arguments
+/// * `index` - the bridge's index in PCI_FUNCTIONS
+pub fn pci_remove_bridge_children(index: usize) {
+    let is_child = |i: usize| unsafe { PCI_FUNCTIONS[i] }.is_some_and(|f| f.parent == Some(index));
+    for i in (0..MAX_PCI_FUNCTIONS).filter(|&i| is_child(i)) {
+        let func = *pci_function(i);
+        if func.header_type == 1 {
+            pci_remove_bridge_children(i);
+        }
+        let mut devs = unsafe { (*addr_of!(PCI_DEVICES)).iter() };
+        let dev = devs.position(|d| d.is_some_and(|d| d.function == i));
+        if let Some(dev) = dev {
+            pci_remove_device(dev);
+        }
+        // The function may already be gone, in which case this goes nowhere.
+        let ecam = Ecam::as_ref(func.bus as usize, func.slot as usize, func.func as usize);
+        ecam.command_reg.write(COMMAND_REG_INTX_DISABLE);
+        println!("PCI {:02x}:{:02x}.{} removed.", func.bus, func.slot, func.func);
+        unsafe {
+            PCI_FUNCTIONS[i] = None;
+        }
+    }
+}
+
+/// # Overview
+/// Walk the capability list, remembering each capability and setting up
+/// MSI or MSI-X for the device.
+fn enum_caps(ecam: &Ecam, dev: &mut PciDevice) {
     let eptr = ecam as *const Ecam as *const u8;
-    if ecam.status_reg >> 4 & 1 != 1 {
+    if ecam.status_reg.read() >> 4 & 1 != 1 {
         // No capabilities
         return;
     }
-    let mut c = unsafe { ecam.typex.type0.capes_pointer };
-    while c != 0 {
-        unsafe {
-            let cap = eptr.add(c as usize) as *mut Capability;
-            c = (*cap).next;
+    // Without IMSICs there is nowhere to send messages. MSI or MSI-X
+    // being enabled turns INTx off, so leave both of them off and let
+    // drivers fall back to INTx or polling.
+    let has_msi = controller().has_msi();
+    let mut msi = None;
+    let mut msix = None;
+    let mut c = ecam.type0().capes_pointer.read();
+    // Guard against a broken list that loops forever.
+    for _ in 0..48 {
+        if c == 0 {
+            break;
+        }
+        let cap = unsafe { eptr.add(c as usize) as *mut Capability };
+        let id = unsafe { (*cap).id.read() };
+        if dev.num_caps < MAX_PCI_CAPS {
+            dev.caps[dev.num_caps] = PciCap { id, offset: c };
+            dev.num_caps += 1;
+        }
+        c = unsafe { (*cap).next.read() };
 
-            if (*cap).id == 0x11 {
-                // MSI-X capability
-                setup_msix(ecam, cap);
-            }
+        match id {
+            // MSI capability
+            0x05 if has_msi => msi = Some(setup_msi(cap)),
+            0x05 => Msi::new(cap).disable(),
+            // MSI-X capability
+            0x11 if has_msi => msix = Some(setup_msix(ecam, cap)),
+            0x11 => disable_msix(cap),
+            _ => {}
         }
     }
+    // A function must not have both enabled. MSI-X is more flexible,
+    // so use it whenever it is there.
+    dev.msi = match (msi, msix) {
+        (_, Some(msix)) => Some(MsiVectors::MsiX(msix)),
+        (Some(msi), None) => Some(MsiVectors::Msi(msi)),
+        (None, None) => None,
+    };
 }
 
-fn setup_msix(ecam: &Ecam, cap: *mut Capability) {
+/// # Overview
+/// Walk the extended capability list, which only PCI Express functions
+/// have. We print the ones that help tell what a device is up to.
+fn enum_ext_caps(ecam: &Ecam, dev: &mut PciDevice) {
+    // PCI Express capability
+    if dev.find_cap(0x10).is_none() {
+        return;
+    }
+    let cfg = ecam as *const Ecam as *const u8;
+    let mut c = EXT_CAP_START;
+    // Each capability is at least 4 bytes, so this stops a broken list
+    // that loops forever.
+    for _ in 0..(4096 - EXT_CAP_START) / 4 {
+        let header = ecam.at::<ExtCapability>(c).header.read();
+        if header == 0 || header == 0xFFFF_FFFF {
+            break;
+        }
+        let cap = PciExtCap {
+            id: header as u16,
+            version: (header >> 16 & 0xF) as u8,
+            offset: c as u16,
+        };
+        if dev.num_ext_caps < MAX_PCI_EXT_CAPS {
+            dev.ext_caps[dev.num_ext_caps] = cap;
+            dev.num_ext_caps += 1;
+        }
+        match cap.id {
+            EXT_CAP_AER | EXT_CAP_DSN | EXT_CAP_ACS | EXT_CAP_SRIOV => print_ext_cap(cfg, &cap),
+            _ => {}
+        }
+        // The bottom two bits of the next pointer are reserved.
+        c = (header >> 20) as usize & !3;
+        if c < EXT_CAP_START {
+            break;
+        }
+    }
+}
+
+/// Find the MSI capability's layout and leave MSI disabled until a
+/// driver allocates vectors through `MsiVectors`.
+fn setup_msi(cap: *mut Capability) -> Msi {
+    let mut msi = Msi::new(cap);
+    msi.disable();
+    for i in 0..msi.len() {
+        msi.mask(i);
+    }
+    println!("MSI vectors = {}, masking = {}", msi.len(), msi.can_mask());
+    msi
+}
+
+/// Find the MSI-X table and PBA and enable MSI-X with every vector masked.
+/// Drivers program and unmask the vectors they use through `MsixTable`.
+fn setup_msix(ecam: &Ecam, cap: *mut Capability) -> MsixTable {
     let msixcapptr = cap as *mut MsixCapability;
     let msixcap = unsafe { msixcapptr.as_ref().unwrap() };
-    let table_offset = msixcap.table & !7;
-    let table_bir = msixcap.table & 7;
-    let pba_offset = msixcap.pba & !7;
-    let pba_bir = msixcap.pba & 7;
+    let table = msixcap.table.read();
+    let pba = msixcap.pba.read();
+    let table_offset = table & !7;
+    let table_bir = table & 7;
+    let pba_offset = pba & !7;
+    let pba_bir = pba & 7;
     // println!("Table offset: 0x{:08x} on {}, PBA offset: 0x{:08x} on {}.", table_offset, table_bir, pba_offset, pba_bir);
     let tabba = get_bar_addr(ecam, table_bir as usize) + table_offset as usize;
     let pbaba = get_bar_addr(ecam, pba_bir as usize) + pba_offset as usize;
     println!("TAB = 0x{:08x}, PBA = 0x{:08x}", tabba, pbaba);
 
-    // Enable MSI-X by setting bit 15 (MSI-X Enable bit)
-    unsafe {
-        write_volatile(&mut (*msixcapptr).msgcontrol, 1 << 15);
+    let tabsize = ((msixcap.msgcontrol.read() & 0x7FF) + 1) as usize;
+    println!("Table size = {}", tabsize);
+
+    let mut msix = MsixTable {
+        cap: msixcapptr,
+        table: tabba as *mut MsixEntry,
+        pba: pbaba as *const Volatile<u32>,
+        size: tabsize,
+    };
+    // Nothing should fire until a driver has programmed the vector.
+    for i in 0..tabsize {
+        msix.mask(i);
     }
+    msix.set_function_mask(false);
 
-    let tabsize = unsafe { (msixcapptr.read_volatile().msgcontrol & 0x3FF) + 1 };
-    println!("Table size = {}", tabsize);
+    // Enable MSI-X by setting bit 15 (MSI-X Enable bit)
+    msixcap.msgcontrol.modify(|msgcontrol| msgcontrol | MSIX_ENABLE);
+
+    msix
+}
 
-    let msixtab = unsafe { (tabba as *mut MsixTable).as_mut().unwrap() };
-    println!("Control reg = 0x{:08x}", msixtab.control);
-    msixtab.addr = IMSIC_M as u64;
-    msixtab.data = 31;
-    msixtab.control = 0;
+/// Turn MSI-X off, which gives the function back its INTx pin.
+fn disable_msix(cap: *mut Capability) {
+    let msixcap = unsafe { (cap as *mut MsixCapability).as_ref().unwrap() };
+    msixcap.msgcontrol.modify(|msgcontrol| msgcontrol & !MSIX_ENABLE);
 }
 
 /// Get the bar address straight from the BAR register. We could store the
@@ -274,7 +2186,12 @@ fn get_bar_addr(ecam: &Ecam, which: usize) -> usize {
     // Strip off the last four bits which do not contribute to the address
     // and are instead used to denote the size of the BAR as well as where
     // the BAR connects 0 = MMIO, 1 = PIO
-    unsafe { ecam.typex.type0.bar[which] as usize & !0xf }
+    let bar = ecam.type0().bar[which].read();
+    let mut addr = (bar & !0xf) as u64;
+    if bar & (BAR_IO | BAR_TYPE_64) == BAR_TYPE_64 && which < 5 {
+        addr |= (ecam.type0().bar[which + 1].read() as u64) << 32;
+    }
+    addr as usize
 }
 
 pub fn pci_init() {
@@ -282,15 +2199,354 @@ pub fn pci_init() {
         println!("PCI subsystem already initialized.");
         return;
     }
-    for bus in 0..=4 {
-        // Typically, there are 8 bits for the bus number, but not
-        // all have to be implemented.
-        let slot_start = if bus == 0 { 1 } else { 0 };
-        for slot in slot_start..32 {
-            pci_setup(bus, slot);
-        }
-    }
+    // Start at the root bus and find everything else by walking
+    // through bridges. Bus numbers are handed out in the order bridges
+    // are found.
+    let mut next_bus = 1;
+    pci_scan_bus(&mut next_bus, 0, None);
+    // Now that we know everything that wants MMIO space, size the
+    // bridge windows from the bottom up and hand out space from the top
+    // down.
+    pci_size_bridges();
+    pci_assign_all();
+    pci_intx_init();
+    pci_enable_all();
     unsafe {
         PCI_INITIALIZED = true;
     }
+    // Root ports report errors to us, so they get a driver right away.
+    pcieport::init();
+}
+
+/// # Overview
+/// Work out which wired source a function's INTx pin ends up on. Every
+/// bridge on the way up swizzles the pin by the slot number, and the
+/// root bus does the same swizzle onto sources 32-35.
+/// # Arguments
+/// * `index` - the function's index in PCI_FUNCTIONS
+/// # Returns
+/// The source number, `None` if the function has no INTx pin
+fn pci_intx_irq(index: usize) -> Option<u32> {
+    let mut func = pci_function(index);
+    let ecam = Ecam::as_ref(func.bus as usize, func.slot as usize, func.func as usize);
+    // The pin is at the same offset in type 0 and type 1 headers.
+    // 0 means no pin, 1-4 are INTA-INTD.
+    let mut pin = match ecam.type0().interrupt_pin.read() {
+        pin @ 1..=4 => pin as usize - 1,
+        _ => return None,
+    };
+    loop {
+        pin = (pin + func.slot as usize) % PCI_INTX_LINES;
+        match func.parent {
+            Some(parent) => func = pci_function(parent),
+            None => break,
+        }
+    }
+    Some(PCI_INTX_IRQ_BASE + pin as u32)
+}
+
+/// # Overview
+/// Route INTA-INTD to hart 0's S mode through the interrupt controller.
+/// The sources stay disabled until a handler is added.
+fn pci_intx_init() {
+    for line in 0..PCI_INTX_LINES {
+        let irq = PCI_INTX_IRQ_BASE + line as u32;
+        if !controller().request(irq, 0, pci_intx_dispatch, line) {
+            println!("INTx IRQ {} is already taken.", irq);
+        }
+    }
+}
+
+/// # Overview
+/// Run every handler sharing an INTx line. Called from the interrupt
+/// controller with the line number as the data.
+fn pci_intx_dispatch(line: usize) {
+    let mut handled = false;
+    for action in unsafe { INTX_HANDLERS[line].iter().flatten() } {
+        handled |= (action.handler)(action.data);
+    }
+    if !handled {
+        let irq = PCI_INTX_IRQ_BASE as usize + line;
+        println!("Unhandled INT{} (IRQ {}).", (b'A' + line as u8) as char, irq);
+    }
+}
+
+/// # Overview
+/// Add a handler to the device's INTx line and let the device assert it.
+/// Drivers use this when a device has no MSI or MSI-X.
+/// # Arguments
+/// * `dev` - the device
+/// * `handler` - called whenever the line is asserted, which may be for
+///   a different device sharing the line
+/// * `data` - passed to the handler
+/// # Returns
+/// `false` if the device has no INTx pin or the line is full
+pub fn pci_request_intx(dev: &PciDevice, handler: IntxHandler, data: usize) -> bool {
+    let irq = match dev.intx {
+        Some(irq) => irq,
+        None => return false,
+    };
+    let actions = unsafe { &mut INTX_HANDLERS[(irq - PCI_INTX_IRQ_BASE) as usize] };
+    let first = actions.iter().all(|a| a.is_none());
+    match actions.iter_mut().find(|a| a.is_none()) {
+        Some(slot) => *slot = Some(IntxAction { handler, data }),
+        None => {
+            println!("Too many handlers on IRQ {}.", irq);
+            return false;
+        }
+    }
+    if first {
+        controller().set_enabled(irq, true);
+    }
+    let ecam = unsafe { &*(dev.config() as *const Ecam) };
+    ecam.command_reg.modify(|c| c & !COMMAND_REG_INTX_DISABLE);
+    true
+}
+
+/// # Overview
+/// Stop the device asserting INTx and remove a handler added with
+/// `pci_request_intx`.
+pub fn pci_free_intx(dev: &PciDevice, handler: IntxHandler, data: usize) {
+    let irq = match dev.intx {
+        Some(irq) => irq,
+        None => return,
+    };
+    let ecam = unsafe { &*(dev.config() as *const Ecam) };
+    ecam.command_reg.modify(|c| c | COMMAND_REG_INTX_DISABLE);
+    let actions = unsafe { &mut INTX_HANDLERS[(irq - PCI_INTX_IRQ_BASE) as usize] };
+    for slot in actions.iter_mut() {
+        if slot.is_some_and(|a| a.handler as usize == handler as usize && a.data == data) {
+            *slot = None;
+        }
+    }
+    if actions.iter().all(|a| a.is_none()) {
+        controller().set_enabled(irq, false);
+    }
+}
+
+/// The name of a class code, as best we can tell from the base class and
+/// subclass.
+fn class_name(base: u8, sub: u8) -> &'static str {
+    match (base, sub) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "Generic system peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, _) => "Serial bus controller",
+        (0xff, _) => "Unassigned class",
+        _ => "Unknown class",
+    }
+}
+
+/// The name of a capability in the standard capability list.
+fn cap_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        0x05 => "MSI",
+        0x09 => "Vendor Specific",
+        0x0d => "Subsystem ID",
+        0x10 => "PCI Express",
+        0x11 => "MSI-X",
+        0x12 => "SATA",
+        0x13 => "Advanced Features",
+        _ => "Unknown",
+    }
+}
+
+/// The device/port type in the PCI Express capabilities register.
+fn pcie_type_name(kind: u16) -> &'static str {
+    match kind {
+        0x0 => "Endpoint",
+        0x1 => "Legacy Endpoint",
+        0x4 => "Root Port",
+        0x5 => "Upstream Port",
+        0x6 => "Downstream Port",
+        0x7 => "PCIe to PCI Bridge",
+        0x8 => "PCI to PCIe Bridge",
+        0x9 => "Root Complex Integrated Endpoint",
+        0xa => "Root Complex Event Collector",
+        _ => "Unknown",
+    }
+}
+
+/// # Overview
+/// Print one capability with whatever details we know how to decode.
+/// # Arguments
+/// * `ecam` - the function's configuration space
+/// * `offset` - where the capability is
+fn lspci_cap(ecam: &Ecam, offset: usize) {
+    let header = ecam.at::<CapabilityHeader>(offset);
+    let id = header.cap.id.read();
+    let control = header.control.read();
+    print!("    [{:02x}] {}", offset, cap_name(id));
+    match id {
+        0x01 => print!(", version {}", control & 7),
+        0x05 => print!(
+            ", {} of {} vectors, {}-bit, masking {}, {}",
+            1 << (control >> 4 & 7),
+            1 << (control >> 1 & 7),
+            if control & MSI_64BIT != 0 { 64 } else { 32 },
+            if control & MSI_PER_VECTOR_MASK != 0 { "yes" } else { "no" },
+            if control & MSI_ENABLE != 0 { "enabled" } else { "disabled" }
+        ),
+        0x10 => print!(
+            " v{}, {}",
+            control & 0xF,
+            pcie_type_name(control >> 4 & 0xF)
+        ),
+        0x11 => {
+            let msix = ecam.at::<MsixCapability>(offset);
+            let (table, pba) = (msix.table.read(), msix.pba.read());
+            print!(
+                ", {} vectors, table BAR{} + 0x{:x}, PBA BAR{} + 0x{:x}, {}{}",
+                (control & 0x7FF) + 1,
+                table & 7,
+                table & !7,
+                pba & 7,
+                pba & !7,
+                if control & MSIX_ENABLE != 0 { "enabled" } else { "disabled" },
+                if control & MSIX_FUNCTION_MASK != 0 { ", masked" } else { "" }
+            )
+        }
+        // The vendor-specific length is the byte after the header.
+        0x09 => print!(", length {}", control & 0xFF),
+        _ => {}
+    }
+    println!();
+}
+
+/// # Overview
+/// Hex dump all 4 KiB of a function's configuration space, 16 bytes a
+/// line. Runs of lines that are all zero are collapsed into a `*`.
+fn lspci_dump(ecam: &Ecam) {
+    // ECAM wants aligned 32-bit reads.
+    let cfg = ecam.at::<[Volatile<u32>; 1024]>(0);
+    let mut skipping = false;
+    for line in (0..4096).step_by(16) {
+        let mut words = [0u32; 4];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = cfg[line / 4 + i].read();
+        }
+        if line != 0 && words == [0; 4] {
+            if !skipping {
+                println!("    *");
+                skipping = true;
+            }
+            continue;
+        }
+        skipping = false;
+        print!("    {:03x}:", line);
+        for word in words.iter() {
+            for byte in word.to_le_bytes().iter() {
+                print!(" {:02x}", byte);
+            }
+        }
+        println!();
+    }
+}
+
+/// # Overview
+/// List every function enumeration found, with its class, BARs and
+/// capabilities.
+/// # Arguments
+/// * `verbose` - also dump the whole configuration space
+pub fn lspci(verbose: bool) {
+    if unsafe { !PCI_INITIALIZED } {
+        println!("PCI has not yet been initialized.");
+        return;
+    }
+    for (index, func) in unsafe { (*addr_of!(PCI_FUNCTIONS)).iter().enumerate() } {
+        let func = match func {
+            Some(func) => func,
+            None => continue,
+        };
+        let ecam = Ecam::as_ref(func.bus as usize, func.slot as usize, func.func as usize);
+        println!(
+            "{:02x}:{:02x}.{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})",
+            func.bus,
+            func.slot,
+            func.func,
+            class_name(ecam.class_basecode.read(), ecam.class_subcode.read()),
+            ecam.class_basecode.read(),
+            ecam.class_subcode.read(),
+            func.vendor_id,
+            func.device_id,
+            ecam.revision_id.read()
+        );
+        let mut devs = unsafe { (*addr_of!(PCI_DEVICES)).iter().flatten() };
+        let dev = devs.find(|d| d.function == index);
+        if let Some(drv) = dev.and_then(|d| d.driver) {
+            println!("    Driver: {}", drv.name());
+        }
+        if let Some(irq) = dev.and_then(|d| d.intx) {
+            let pin = ecam.type0().interrupt_pin.read();
+            println!("    Interrupt: pin {} routed to IRQ {}", (b'A' + pin - 1) as char, irq);
+        }
+        for (i, bar) in func.bars.iter().enumerate().filter(|(_, b)| b.size != 0) {
+            print!(
+                "    BAR{}: {}-bit{} memory, size 0x{:x}",
+                i,
+                if bar.is64 { 64 } else { 32 },
+                if bar.prefetchable { " prefetchable" } else { "" },
+                bar.size
+            );
+            match bar.addr {
+                Some(addr) => println!(" at 0x{:x}", addr),
+                None => println!(", unassigned"),
+            }
+        }
+        if func.header_type == 1 {
+            println!(
+                "    Bus: secondary {:02x}, subordinate {:02x}",
+                func.secondary_bus, func.subordinate_bus
+            );
+            for (name, window) in [("Memory", &func.mem), ("Prefetchable", &func.pref)] {
+                if window.size != 0 && window.base != 0 {
+                    println!(
+                        "    {} window: 0x{:x}-0x{:x}",
+                        name,
+                        window.base,
+                        window.base + window.size - 1
+                    );
+                }
+            }
+        }
+        let cfg = ecam as *const Ecam as *const u8;
+        if ecam.status_reg.read() >> 4 & 1 == 1 {
+            println!("    Capabilities:");
+            let mut c = ecam.type0().capes_pointer.read() as usize;
+            // Guard against a broken list that loops forever.
+            let mut count = 0;
+            while c != 0 && count < 48 {
+                lspci_cap(ecam, c);
+                c = ecam.at::<Capability>(c).next.read() as usize;
+                count += 1;
+            }
+        }
+        if let Some(dev) = dev.filter(|d| d.num_ext_caps != 0) {
+            println!("    Extended capabilities:");
+            for cap in dev.ext_caps() {
+                print_ext_cap(cfg, cap);
+            }
+        }
+        if verbose {
+            lspci_dump(ecam);
+        }
+    }
 }
@@ -0,0 +1,491 @@
+//! pcieport.rs
+//! PCI Express root port services
+//! Stephen Marz
+//! 17-Oct-2026
+
+use crate::{
+    imsic::{imsic_disable_on, imsic_enable, imsic_free, imsic_register, PrivMode},
+    pci::{
+        pci_find_device, pci_function, pci_register_driver, pci_remove_bridge_children,
+        pci_rescan_bridge, AerCapability, PciDevice, PciDeviceId, PciDriver, PcieCapability,
+        PCI_DEVICES,
+    },
+    timer::{sleep_ms, timer_deadline, timer_expired},
+};
+use core::ptr::addr_of;
+
+// Device/port type in the PCI Express capabilities register
+const PCIE_TYPE_ROOT_PORT: u16 = 0x4;
+
+// Slot capabilities
+const SLOT_CAPS_POWER_CONTROLLER: u32 = 1 << 1;
+
+// Slot control: interrupt on attention button pressed, presence detect
+// changed and data link layer state changed, and the hot-plug
+// interrupt enable that covers them all.
+const SLOT_CONTROL_ABPE: u16 = 1 << 0;
+const SLOT_CONTROL_PDCE: u16 = 1 << 3;
+const SLOT_CONTROL_HPIE: u16 = 1 << 5;
+const SLOT_CONTROL_DLLSCE: u16 = 1 << 12;
+// The power indicator (2 bits) and power controller control (1 = off)
+const SLOT_CONTROL_PIC: u16 = 0b11 << 8;
+const SLOT_CONTROL_PIC_ON: u16 = 0b01 << 8;
+const SLOT_CONTROL_PIC_OFF: u16 = 0b11 << 8;
+const SLOT_CONTROL_PCC: u16 = 1 << 10;
+
+// Every slot interrupt we turn on
+const SLOT_CONTROL_EVENTS: u16 =
+    SLOT_CONTROL_ABPE | SLOT_CONTROL_PDCE | SLOT_CONTROL_DLLSCE | SLOT_CONTROL_HPIE;
+
+// Slot status. The events are write 1 to clear.
+const SLOT_STATUS_ABP: u16 = 1 << 0;
+const SLOT_STATUS_PDC: u16 = 1 << 3;
+const SLOT_STATUS_PDS: u16 = 1 << 6;
+const SLOT_STATUS_DLLSC: u16 = 1 << 8;
+const SLOT_STATUS_EVENTS: u16 = SLOT_STATUS_ABP | SLOT_STATUS_PDC | SLOT_STATUS_DLLSC;
+
+// mstatus.MIE turns M-mode interrupts on and off
+const MSTATUS_MIE: usize = 1 << 3;
+
+// Link status: the data link layer is up
+const LINK_STATUS_DLLLA: u16 = 1 << 13;
+
+// How long a link gets to come up after the slot is powered, and how
+// long the device then gets before we send it config requests. The
+// spec asks for 100 ms after the link is up.
+const LINK_UP_MS: u64 = 1000;
+const LINK_SETTLE_MS: u64 = 100;
+
+// Device control: report correctable, non-fatal, fatal and
+// unsupported request errors.
+const DEVICE_CONTROL_REPORTING: u16 = 0xf;
+
+// Root control: system errors on correctable, non-fatal and fatal
+// errors. We take them as AER interrupts instead.
+const ROOT_CONTROL_SERR: u16 = 0x7;
+const ROOT_CONTROL_PME_IE: u16 = 1 << 3;
+
+// Root status: a PME was received
+const ROOT_STATUS_PME: u32 = 1 << 16;
+
+// Root error command: interrupt on correctable, non-fatal and fatal errors
+const ROOT_ERROR_COMMAND_ENABLE: u32 = 0x7;
+
+// Root error status bits
+const ROOT_ERROR_COR: u32 = 1 << 0;
+const ROOT_ERROR_MULTI_COR: u32 = 1 << 1;
+const ROOT_ERROR_UNCOR: u32 = 1 << 2;
+const ROOT_ERROR_MULTI_UNCOR: u32 = 1 << 3;
+const ROOT_ERROR_FATAL: u32 = 1 << 6;
+
+// PCI bridge class
+static PORT_IDS: [PciDeviceId; 1] = [PciDeviceId::class(0x06_04_00, 0xFF_FF_00)];
+
+const MAX_PORTS: usize = 8;
+
+/// What we keep for each root port we drive.
+#[derive(Clone, Copy)]
+struct PciePort {
+    bus: u8,
+    slot: u8,
+    func: u8,
+    // Index of the port in PCI_FUNCTIONS
+    function: usize,
+    // The port's PCI Express capability
+    pcie: *const PcieCapability,
+    // The EIID the port's vector sends, if it has one, and the hart
+    // whose interrupt file it goes to
+    eiid: Option<u32>,
+    hart: usize,
+    // Whether the slot is hot-plug capable, and whether we think there is
+    // something in it
+    hotplug: bool,
+    populated: bool,
+    // Slot events the vector saw that poll() hasn't handled yet
+    events: u16,
+}
+
+impl PciePort {
+    fn pcie(&self) -> &PcieCapability {
+        unsafe { &*self.pcie }
+    }
+}
+
+static mut PORTS: [Option<PciePort>; MAX_PORTS] = [None; MAX_PORTS];
+
+static mut PORT_INITIALIZED: bool = false;
+
+struct PciePortDriver;
+
+impl PciDriver for PciePortDriver {
+    fn name(&self) -> &'static str {
+        "pcieport"
+    }
+
+    fn id_table(&self) -> &'static [PciDeviceId] {
+        &PORT_IDS
+    }
+
+    fn probe(&self, dev: &mut PciDevice, _id: &PciDeviceId) -> bool {
+        match port_setup(dev) {
+            Some(index) => {
+                dev.driver_data = index;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&self, dev: &mut PciDevice) {
+        if let Some(port) = unsafe { PORTS[dev.driver_data].take() } {
+            // Stop the port from interrupting us.
+            if let Some(aer) = dev.aer() {
+                aer.root_command.write(0);
+            }
+            let pcie = port.pcie();
+            pcie.root_control.modify(|ctl| ctl & !ROOT_CONTROL_PME_IE);
+            if port.hotplug {
+                pcie.slot_control.modify(|ctl| ctl & !SLOT_CONTROL_EVENTS);
+            }
+            if let (Some(msi), Some(eiid)) = (dev.msi.as_mut(), port.eiid) {
+                msi.mask(0);
+                imsic_disable_on(port.hart, PrivMode::Machine, eiid);
+                imsic_free(port.hart, PrivMode::Machine, eiid);
+            }
+        }
+    }
+}
+
+static PORT_DRIVER: PciePortDriver = PciePortDriver;
+
+/// # Overview
+/// Take over a root port: give it an MSI vector, then turn on error
+/// reporting for it and everything behind it.
+/// # Returns
+/// The port's index in PORTS, `None` if it isn't a root port we can use
+fn port_setup(dev: &mut PciDevice) -> Option<usize> {
+    let pcie = dev.pcie()?;
+    if pcie.caps.read() >> 4 & 0xF != PCIE_TYPE_ROOT_PORT {
+        return None;
+    }
+    let index = match unsafe { (*addr_of!(PORTS)).iter().position(|p| p.is_none()) } {
+        Some(index) => index,
+        None => {
+            println!("Too many PCIe ports.");
+            return None;
+        }
+    };
+
+    // Root ports only ever have one vector for AER, PME and hotplug.
+    let hart = csr_read!("mhartid");
+    let mut eiids = [0];
+    let vectors = match dev.msi.as_mut() {
+        Some(msi) => msi.alloc(hart, PrivMode::Machine, &mut eiids),
+        None => 0,
+    };
+    let eiid = if vectors == 1 {
+        imsic_register(hart, PrivMode::Machine, eiids[0], port_irq, index);
+        imsic_enable(PrivMode::Machine, eiids[0] as usize);
+        if let Some(msi) = dev.msi.as_mut() {
+            msi.unmask(0);
+        }
+        Some(eiids[0])
+    } else {
+        println!("PCIe port {:02x}:{:02x}.{} has no MSI.", dev.bus, dev.slot, dev.func);
+        None
+    };
+    let port = PciePort {
+        bus: dev.bus,
+        slot: dev.slot,
+        func: dev.func,
+        function: dev.function,
+        pcie,
+        eiid,
+        hart,
+        hotplug: pci_function(dev.function).hotplug,
+        populated: false,
+        events: 0,
+    };
+    unsafe {
+        PORTS[index] = Some(port);
+    }
+
+    // We handle errors through AER interrupts, not system errors, and
+    // we want to hear about PMEs.
+    pcie.root_control.modify(|ctl| ctl & !ROOT_CONTROL_SERR | ROOT_CONTROL_PME_IE);
+    pcie.root_status.write(ROOT_STATUS_PME);
+    port_enable_reporting(&port);
+    if port.hotplug {
+        port_hotplug_enable(index);
+    }
+    if let Some(aer) = dev.aer() {
+        // Clear anything left over from before we got here.
+        aer.clear(u32::MAX, u32::MAX);
+        aer.root_status.write(u32::MAX);
+        aer.root_command.write(ROOT_ERROR_COMMAND_ENABLE);
+    }
+    Some(index)
+}
+
+/// # Overview
+/// Have the port and every PCI Express function behind it report errors.
+fn port_enable_reporting(port: &PciePort) {
+    let (first, last) = port_buses(port);
+    for dev in unsafe { (*addr_of!(PCI_DEVICES)).iter().flatten() } {
+        let is_port = dev.function == port.function;
+        if !is_port && !(first..=last).contains(&dev.bus) {
+            continue;
+        }
+        if let Some(pcie) = dev.pcie() {
+            pcie.device_control.modify(|ctl| ctl | DEVICE_CONTROL_REPORTING);
+        }
+    }
+}
+
+/// The buses behind a port.
+fn port_buses(port: &PciePort) -> (u8, u8) {
+    let func = pci_function(port.function);
+    (func.secondary_bus, func.subordinate_bus)
+}
+
+/// # Overview
+/// The port's vector. Called from the IMSIC with the port's index in
+/// PORTS as the data.
+fn port_irq(index: usize) {
+    let port = match unsafe { PORTS[index] } {
+        Some(port) => port,
+        None => return,
+    };
+    let dev = match pci_find_device(port.bus, port.slot, port.func) {
+        Some((_, dev)) => dev,
+        None => return,
+    };
+    let pcie = port.pcie();
+    let status = pcie.root_status.read();
+    if status & ROOT_STATUS_PME != 0 {
+        let id = status as u16;
+        println!("PCIe PME from {:02x}:{:02x}.{}.", id >> 8, id >> 3 & 0x1F, id & 7);
+        pcie.root_status.write(ROOT_STATUS_PME);
+    }
+    if port.hotplug {
+        // Clear the events now so the port can interrupt again, and leave
+        // the work for poll(). Probing a driver waits on interrupts, which
+        // it can't do from in here.
+        let events = pcie.slot_status.read() & SLOT_STATUS_EVENTS;
+        if events != 0 {
+            pcie.slot_status.write(events);
+            if let Some(port) = unsafe { PORTS[index].as_mut() } {
+                port.events |= events;
+            }
+        }
+    }
+    if let Some(aer) = dev.aer() {
+        port_aer(&port, aer);
+    }
+}
+
+/// # Overview
+/// Note what is in the slot and turn on the hot-plug interrupts.
+/// # Arguments
+/// * `index` - the port's index in PORTS
+fn port_hotplug_enable(index: usize) {
+    let port = match unsafe { PORTS[index].as_mut() } {
+        Some(port) => port,
+        None => return,
+    };
+    let pcie = unsafe { &*port.pcie };
+    port.populated = pcie.slot_status.read() & SLOT_STATUS_PDS != 0;
+    pcie.slot_status.write(SLOT_STATUS_EVENTS);
+    pcie.slot_control.modify(|ctl| ctl | SLOT_CONTROL_EVENTS);
+    println!(
+        "PCIe port {:02x}:{:02x}.{} hot-plug slot is {}.",
+        port.bus,
+        port.slot,
+        port.func,
+        if port.populated { "occupied" } else { "empty" }
+    );
+}
+
+/// # Overview
+/// Turn the slot's power (and power indicator) on or off. QEMU removes
+/// a device whose removal was requested once its slot is powered off.
+fn port_slot_power(port: &PciePort, on: bool) {
+    let pcie = port.pcie();
+    if pcie.slot_caps.read() & SLOT_CAPS_POWER_CONTROLLER == 0 {
+        return;
+    }
+    pcie.slot_control.modify(|ctl| {
+        let ctl = ctl & !(SLOT_CONTROL_PIC | SLOT_CONTROL_PCC);
+        match on {
+            true => ctl | SLOT_CONTROL_PIC_ON,
+            false => ctl | SLOT_CONTROL_PIC_OFF | SLOT_CONTROL_PCC,
+        }
+    });
+}
+
+/// # Overview
+/// Handle the slot events a port's vector saw. The attention button asks
+/// for the slot to be emptied (or filled, if it is empty), and presence or
+/// link changes tell us a device came or went.
+/// # Arguments
+/// * `index` - the port's index in PORTS
+/// * `events` - the slot events the vector saw
+fn port_hotplug(index: usize, events: u16) {
+    // Work on a copy. Rescanning and removing devices behind the port
+    // goes through the driver's probe and remove, which change PORTS.
+    let port = match unsafe { PORTS[index] } {
+        Some(port) => port,
+        None => return,
+    };
+    // The port may have gone away with a bridge above it.
+    if pci_find_device(port.bus, port.slot, port.func).is_none() {
+        return;
+    }
+    let status = port.pcie().slot_status.read();
+    let present = status & SLOT_STATUS_PDS != 0;
+    let name = (port.bus, port.slot, port.func);
+    let insert = match (events & SLOT_STATUS_ABP != 0, port.populated) {
+        // The button toggles the slot.
+        (true, populated) => !populated && present,
+        // Otherwise, follow what is actually in the slot.
+        (false, populated) if present != populated => present,
+        _ => return,
+    };
+    if insert {
+        println!("PCIe port {:02x}:{:02x}.{}: device inserted.", name.0, name.1, name.2);
+        port_slot_power(&port, true);
+        let pcie = port.pcie();
+        let link_up = || pcie.link_status.read() & LINK_STATUS_DLLLA != 0;
+        let deadline = timer_deadline(LINK_UP_MS);
+        while !link_up() && !timer_expired(deadline) {}
+        if link_up() {
+            sleep_ms(LINK_SETTLE_MS);
+        } else {
+            println!("PCIe port link did not come up.");
+        }
+        port_set_populated(index, true);
+        if pci_rescan_bridge(port.function) == 0 {
+            println!("Nothing found behind the port.");
+        }
+        port_enable_reporting(&port);
+    } else if port.populated {
+        println!("PCIe port {:02x}:{:02x}.{}: device removed.", name.0, name.1, name.2);
+        pci_remove_bridge_children(port.function);
+        port_slot_power(&port, false);
+        port_set_populated(index, false);
+    }
+}
+
+/// Record whether we think there is something in a port's slot.
+fn port_set_populated(index: usize, populated: bool) {
+    if let Some(port) = unsafe { PORTS[index].as_mut() } {
+        port.populated = populated;
+    }
+}
+
+/// # Overview
+/// Run the hot-plug work the port vectors left for us. The console calls
+/// this whenever it is idle.
+pub fn poll() {
+    for index in 0..MAX_PORTS {
+        let events = port_take_events(index);
+        if events != 0 {
+            port_hotplug(index, events);
+        }
+    }
+}
+
+/// # Overview
+/// Take the slot events a port's vector has saved up. The vector ORs
+/// more in, so this is done with interrupts off.
+/// # Returns
+/// The events, 0 if there are none or there is no such port
+fn port_take_events(index: usize) -> u16 {
+    let mstatus = csr_read!("mstatus");
+    csr_write!("mstatus", mstatus & !MSTATUS_MIE);
+    let events = match unsafe { PORTS[index].as_mut() } {
+        Some(port) => core::mem::take(&mut port.events),
+        None => 0,
+    };
+    csr_write!("mstatus", mstatus);
+    events
+}
+
+/// # Overview
+/// Decode an AER interrupt. The root port tells us the first function
+/// that sent each kind of error message, but more may have been
+/// received, so we also check everything behind the port.
+fn port_aer(port: &PciePort, aer: &AerCapability) {
+    let status = aer.root_status.read();
+    if status & (ROOT_ERROR_COR | ROOT_ERROR_UNCOR) == 0 {
+        return;
+    }
+    let source = aer.error_source_id.read();
+    if status & ROOT_ERROR_COR != 0 {
+        let id = source as u16;
+        println!(
+            "PCIe AER: correctable error{} from {:02x}:{:02x}.{}",
+            if status & ROOT_ERROR_MULTI_COR != 0 { "s" } else { "" },
+            id >> 8,
+            id >> 3 & 0x1F,
+            id & 7
+        );
+    }
+    if status & ROOT_ERROR_UNCOR != 0 {
+        let id = (source >> 16) as u16;
+        println!(
+            "PCIe AER: {} error{} from {:02x}:{:02x}.{}",
+            if status & ROOT_ERROR_FATAL != 0 { "fatal" } else { "non-fatal" },
+            if status & ROOT_ERROR_MULTI_UNCOR != 0 { "s" } else { "" },
+            id >> 8,
+            id >> 3 & 0x1F,
+            id & 7
+        );
+    }
+
+    // Log and clear every function with something in its status.
+    let (first, last) = port_buses(port);
+    for dev in unsafe { (*addr_of!(PCI_DEVICES)).iter().flatten() } {
+        let is_port = dev.function == port.function;
+        if !is_port && !(first..=last).contains(&dev.bus) {
+            continue;
+        }
+        let aer = match dev.aer() {
+            Some(aer) => aer,
+            None => continue,
+        };
+        let (unc, cor) = (aer.uncorrectable(), aer.correctable());
+        if unc == 0 && cor == 0 {
+            continue;
+        }
+        println!(
+            "  {:02x}:{:02x}.{} {:04x}:{:04x}",
+            dev.bus, dev.slot, dev.func, dev.vendor_id, dev.device_id
+        );
+        aer.print_status();
+        if unc != 0 {
+            let log = aer.header();
+            println!(
+                "    TLP header: {:08x} {:08x} {:08x} {:08x}",
+                log[0], log[1], log[2], log[3]
+            );
+        }
+        aer.clear(unc, cor);
+    }
+    // The root error status is write 1 to clear.
+    aer.root_status.write(status);
+}
+
+/// # Overview
+/// Register the port driver, which takes every root port enumeration
+/// found.
+pub fn init() {
+    if unsafe { PORT_INITIALIZED } {
+        return;
+    }
+    if pci_register_driver(&PORT_DRIVER) {
+        unsafe {
+            PORT_INITIALIZED = true;
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! plic.rs
+//! SiFive Platform Level Interrupt Controller (PLIC)
+//! Stephen Marz
+//! 17-Oct-2026
+
+use crate::{volatile::Volatile, MAX_HARTS};
+
+// QEMU's virt machine puts the PLIC where the M-mode APLIC would be
+// when started with aia=none.
+const PLIC_BASE: usize = 0xc00_0000;
+// One 32-bit priority per source
+const PLIC_PRIORITY: usize = PLIC_BASE;
+// One enable bit per source for each context, 0x80 bytes per context
+const PLIC_ENABLE: usize = PLIC_BASE + 0x2000;
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+// The threshold and claim/complete registers for each context
+const PLIC_CONTEXT: usize = PLIC_BASE + 0x20_0000;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+
+// Sources are 1..=1023. Source 0 means "no interrupt".
+const PLIC_NUM_SOURCES: usize = 1024;
+
+// virt has 7 priority levels. A priority of 0 never interrupts and,
+// unlike the APLIC, higher numbers are more urgent. We don't need any
+// ordering, so every source gets the same priority.
+const PLIC_PRIORITY_DEFAULT: u32 = 1;
+
+/// A handler for a PLIC source. The argument is the `data` value given
+/// when the handler was registered.
+pub type PlicHandler = fn(usize);
+
+#[derive(Clone, Copy)]
+struct PlicSource {
+    handler: PlicHandler,
+    data: usize,
+    hart: usize,
+}
+
+static mut PLIC_SOURCES: [Option<PlicSource>; PLIC_NUM_SOURCES] = [None; PLIC_NUM_SOURCES];
+
+/// The per-context registers
+#[repr(C)]
+struct PlicContext {
+    pub threshold: Volatile<u32>,
+    pub claim: Volatile<u32>,
+}
+
+impl PlicContext {
+    /// # Overview
+    /// Get a hart's S-mode context. virt gives every hart an M-mode
+    /// context followed by an S-mode context.
+    /// # Arguments
+    /// `hart` - the HART number for the context to get
+    /// # Returns
+    /// A reference to the context registers
+    pub fn as_ref<'a>(hart: usize) -> &'a Self {
+        assert!(hart < MAX_HARTS);
+        let k = PLIC_CONTEXT + context(hart) * PLIC_CONTEXT_STRIDE;
+        unsafe { (k as *const Self).as_ref().unwrap() }
+    }
+}
+
+const fn context(hart: usize) -> usize {
+    hart * 2 + 1
+}
+
+fn register<'a>(addr: usize) -> &'a Volatile<u32> {
+    unsafe { (addr as *const Volatile<u32>).as_ref().unwrap() }
+}
+
+/// # Overview
+/// Let every priority through to a hart's S-mode context.
+/// # Arguments
+/// `hart` - the hart to set up
+pub fn plic_init(hart: usize) {
+    PlicContext::as_ref(hart).threshold.write(0);
+}
+
+/// # Overview
+/// Send a source to a hart's S mode and call a handler when it fires.
+/// The source is left disabled.
+/// # Arguments
+/// * `irq` - the wired interrupt source
+/// * `hart` - the hart to interrupt
+/// * `handler` - called with `data` when the source fires
+/// # Returns
+/// `false` if the source already has a handler
+pub fn plic_request(irq: u32, hart: usize, handler: PlicHandler, data: usize) -> bool {
+    assert!(irq > 0 && (irq as usize) < PLIC_NUM_SOURCES);
+    let slot = unsafe { &mut PLIC_SOURCES[irq as usize] };
+    if slot.is_some() {
+        return false;
+    }
+    *slot = Some(PlicSource { handler, data, hart });
+    register(PLIC_PRIORITY + 4 * irq as usize).write(PLIC_PRIORITY_DEFAULT);
+    true
+}
+
+fn enable_register<'a>(irq: u32, hart: usize) -> &'a Volatile<u32> {
+    register(PLIC_ENABLE + context(hart) * PLIC_ENABLE_STRIDE + 4 * (irq as usize / 32))
+}
+
+/// # Overview
+/// Enable or disable a source added with `plic_request` on the hart it
+/// was sent to.
+pub fn plic_set_enabled(irq: u32, enabled: bool) {
+    let hart = match unsafe { PLIC_SOURCES[irq as usize] } {
+        Some(source) => source.hart,
+        None => return,
+    };
+    let bit = 1 << (irq % 32);
+    enable_register(irq, hart).modify(|v| if enabled { v | bit } else { v & !bit });
+}
+
+/// # Overview
+/// Move a source added with `plic_request` to another hart. The PLIC
+/// keeps the source pending until some context claims it, so moving the
+/// enable bit from one context to the other can't lose it.
+/// # Returns
+/// `false` if the source has no handler
+pub fn plic_set_affinity(irq: u32, hart: usize) -> bool {
+    assert!(hart < MAX_HARTS);
+    let source = match unsafe { PLIC_SOURCES[irq as usize].as_mut() } {
+        Some(source) => source,
+        None => return false,
+    };
+    let bit = 1 << (irq % 32);
+    let enabled = enable_register(irq, source.hart).read() & bit != 0;
+    enable_register(irq, source.hart).modify(|v| v & !bit);
+    source.hart = hart;
+    if enabled {
+        enable_register(irq, hart).modify(|v| v | bit);
+    }
+    true
+}
+
+/// # Overview
+/// Handle an S-mode external interrupt. Called from `trap::rust_trap`
+/// through the interrupt controller.
+pub fn plic_handle() {
+    let hart = csr_read!("mhartid");
+    let context = PlicContext::as_ref(hart);
+    match context.claim.read() {
+        0 => println!("Spurious PLIC interrupt."),
+        irq => {
+            match unsafe { PLIC_SOURCES[irq as usize] } {
+                Some(source) => (source.handler)(source.data),
+                None => println!("Unknown PLIC IRQ #{}", irq),
+            }
+            // Writing the source back completes it, so the PLIC can
+            // send it to us again.
+            context.claim.write(irq);
+        }
+    }
+}
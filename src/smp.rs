@@ -0,0 +1,108 @@
+//! smp.rs
+//! Bringing up the secondary harts
+//! Stephen Marz
+//! 17-Oct-2026
+
+use crate::{
+    imsic::imsic_hart_init,
+    ipi::{ipi_hart_init, ipi_send, IpiReason},
+    irq::controller,
+    timer::{timer_deadline, timer_expired, timer_hart_init},
+    MAX_HARTS,
+};
+use core::{arch::asm, ptr::read_volatile, ptr::write_volatile};
+
+// mstatus.MIE turns M-mode interrupts on and off
+const MSTATUS_MIE: usize = 1 << 3;
+
+// How long hart 0 waits for a hart it woke to say it is up
+const SMP_ONLINE_MS: u64 = 1000;
+
+// Set by hart 0 to let a secondary hart out of its wait loop. The IPI
+// that wakes it just gets it out of wfi.
+static mut HART_RELEASED: [bool; MAX_HARTS] = [false; MAX_HARTS];
+// Set by each hart once it is running
+static mut HART_ONLINE: [bool; MAX_HARTS] = [false; MAX_HARTS];
+
+/// # Overview
+/// See whether a hart is up.
+pub fn hart_online(hart: usize) -> bool {
+    hart < MAX_HARTS && unsafe { read_volatile(&HART_ONLINE[hart]) }
+}
+
+/// # Overview
+/// Let a secondary hart out of its wait loop and wake it up.
+fn smp_release(hart: usize) {
+    unsafe {
+        write_volatile(&mut HART_RELEASED[hart], true);
+    }
+    // ipi_send fences, so the release is visible before the IPI lands.
+    ipi_send(hart, IpiReason::Reschedule);
+}
+
+/// # Overview
+/// Wake every secondary hart and wait for each one to come up. Called by
+/// hart 0 once the interrupt controller is set up.
+/// # Returns
+/// The number of harts running, including hart 0
+pub fn smp_boot() -> usize {
+    unsafe {
+        write_volatile(&mut HART_ONLINE[0], true);
+    }
+    if !controller().has_msi() {
+        println!("Secondary harts need IMSICs to be woken. Running on hart 0 only.");
+        return 1;
+    }
+    ipi_hart_init(0);
+    let mut online = 1;
+    for hart in 1..MAX_HARTS {
+        smp_release(hart);
+        let deadline = timer_deadline(SMP_ONLINE_MS);
+        while !hart_online(hart) && !timer_expired(deadline) {}
+        if hart_online(hart) {
+            online += 1;
+        } else {
+            println!("Hart {} did not come up.", hart);
+        }
+    }
+    println!("{} harts online.", online);
+    online
+}
+
+/// # Overview
+/// Where every hart but hart 0 goes after main sets its trap frame. It
+/// sets up its interrupt files, waits for hart 0 to wake it, and then
+/// idles, doing its work in interrupts (IPIs from the other harts). This
+/// returns (and parks the hart) only if there are no IMSICs to wake it
+/// with.
+/// # Arguments
+/// * `hart` - this hart's ID
+pub fn smp_secondary(hart: usize) {
+    if !controller().has_msi() {
+        return;
+    }
+    // Wait with interrupts off. A message that lands between the check
+    // and the wfi would otherwise be taken right there and the wfi would
+    // never end. wfi still wakes up for it, and it is taken once we turn
+    // interrupts back on. If it was sent before we enabled it, it is
+    // already pending.
+    let mstatus = csr_read!("mstatus");
+    csr_write!("mstatus", mstatus & !MSTATUS_MIE);
+    imsic_hart_init();
+    ipi_hart_init(hart);
+    timer_hart_init();
+    while !unsafe { read_volatile(&HART_RELEASED[hart]) } {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+    csr_write!("mstatus", mstatus);
+    unsafe {
+        write_volatile(&mut HART_ONLINE[hart], true);
+    }
+    loop {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
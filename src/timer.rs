@@ -0,0 +1,261 @@
+//! timer.rs
+//! ACLINT machine timer (MTIMER)
+//! Stephen Marz
+//! 17-Oct-2026
+
+use crate::MAX_HARTS;
+use core::{
+    arch::asm,
+    ptr::{read_volatile, write_volatile},
+};
+
+// The MTIMER on virt. With aclint=on it is still at the old CLINT
+// addresses: one mtimecmp per hart, 8 bytes apart, and one mtime for
+// everybody at the end.
+const MTIMECMP: usize = 0x200_4000;
+const MTIME: usize = 0x200_bff8;
+
+// virt's timebase-frequency. mtime goes up this many times a second.
+pub const TIMER_FREQ: u64 = 10_000_000;
+
+// mie.MTIE turns the machine timer interrupt (cause 7) on and off
+const MIE_MTIE: usize = 1 << 7;
+// mstatus.MIE turns M-mode interrupts on and off
+const MSTATUS_MIE: usize = 1 << 3;
+
+// How many timers each hart can have waiting at once
+const TIMER_SLOTS: usize = 16;
+
+/// A function called when a timer goes off. The argument is the `data`
+/// value given when the timer was added. It runs in the timer interrupt.
+pub type TimerHandler = fn(usize);
+
+#[derive(Clone, Copy)]
+struct TimerEvent {
+    // mtime value to go off at
+    deadline: u64,
+    // Ticks between each time a periodic timer goes off, 0 for one-shot
+    period: u64,
+    handler: TimerHandler,
+    data: usize,
+}
+
+// Each hart's timer queue. A hart only ever touches its own, and only
+// with interrupts off.
+static mut TIMERS: [[Option<TimerEvent>; TIMER_SLOTS]; MAX_HARTS] =
+    [[None; TIMER_SLOTS]; MAX_HARTS];
+
+fn mtimecmp(hart: usize) -> *mut u32 {
+    (MTIMECMP + 8 * hart) as *mut u32
+}
+
+/// # Overview
+/// Read mtime. We are RV32, so it is read in two halves. If the low half
+/// wraps between reading them, the high half changes, so read until it
+/// doesn't.
+/// # Returns
+/// The number of ticks since the machine started
+pub fn timer_now() -> u64 {
+    let mtime = MTIME as *const u32;
+    loop {
+        let hi = unsafe { read_volatile(mtime.add(1)) };
+        let lo = unsafe { read_volatile(mtime) };
+        if hi == unsafe { read_volatile(mtime.add(1)) } {
+            return (hi as u64) << 32 | lo as u64;
+        }
+    }
+}
+
+/// # Overview
+/// The monotonic clock in milliseconds.
+pub fn timer_ms() -> u64 {
+    timer_now() / (TIMER_FREQ / 1000)
+}
+
+/// # Overview
+/// The monotonic clock in microseconds.
+pub fn timer_us() -> u64 {
+    timer_now() / (TIMER_FREQ / 1_000_000)
+}
+
+pub const fn ms_to_ticks(ms: u64) -> u64 {
+    ms * (TIMER_FREQ / 1000)
+}
+
+/// # Overview
+/// Get the mtime value some number of milliseconds from now, for loops
+/// that poll something until `timer_expired`.
+pub fn timer_deadline(ms: u64) -> u64 {
+    timer_now() + ms_to_ticks(ms)
+}
+
+/// # Overview
+/// See whether a deadline from `timer_deadline` has passed.
+pub fn timer_expired(deadline: u64) -> bool {
+    timer_now() >= deadline
+}
+
+/// # Overview
+/// Set this hart's mtimecmp. The high half is set to its largest value
+/// first, so the timer can't go off early with only the low half written.
+fn set_mtimecmp(hart: usize, deadline: u64) {
+    let cmp = mtimecmp(hart);
+    unsafe {
+        write_volatile(cmp, u32::MAX);
+        write_volatile(cmp.add(1), (deadline >> 32) as u32);
+        write_volatile(cmp, deadline as u32);
+    }
+}
+
+/// # Overview
+/// Point mtimecmp at the soonest timer in this hart's queue, or turn it
+/// off if the queue is empty.
+fn timer_program(hart: usize) {
+    let next = unsafe { TIMERS[hart].iter() }
+        .flatten()
+        .map(|event| event.deadline)
+        .min()
+        .unwrap_or(u64::MAX);
+    set_mtimecmp(hart, next);
+}
+
+/// # Overview
+/// Run something with this hart's interrupts off, so the timer interrupt
+/// can't change the queue underneath it.
+fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let mstatus = csr_read!("mstatus");
+    csr_write!("mstatus", mstatus & !MSTATUS_MIE);
+    let ret = f();
+    csr_write!("mstatus", mstatus);
+    ret
+}
+
+/// # Overview
+/// Turn off this hart's timer and then turn on its interrupt. mtimecmp
+/// is 0 out of reset, so turning the interrupt on first would make it go
+/// off right away. Every hart runs this for itself.
+pub fn timer_hart_init() {
+    let hart = csr_read!("mhartid");
+    set_mtimecmp(hart, u64::MAX);
+    unsafe {
+        asm!("csrs mie, {}", in(reg) MIE_MTIE);
+    }
+}
+
+fn timer_add(deadline: u64, period: u64, handler: TimerHandler, data: usize) -> Option<usize> {
+    let hart = csr_read!("mhartid");
+    without_interrupts(|| {
+        let timers = unsafe { &mut TIMERS[hart] };
+        let slot = timers.iter().position(|t| t.is_none())?;
+        timers[slot] = Some(TimerEvent { deadline, period, handler, data });
+        timer_program(hart);
+        Some(slot)
+    })
+}
+
+/// # Overview
+/// Call a handler once on this hart after some time.
+/// # Arguments
+/// * `ms` - how many milliseconds to wait
+/// * `handler` - called with `data` when the time is up
+/// # Returns
+/// The timer's ID for `timer_cancel`, or `None` if this hart has no
+/// free timers
+pub fn timer_oneshot(ms: u64, handler: TimerHandler, data: usize) -> Option<usize> {
+    timer_add(timer_deadline(ms), 0, handler, data)
+}
+
+/// # Overview
+/// Call a handler on this hart every so often until it is cancelled.
+/// # Arguments
+/// * `ms` - how many milliseconds between each call
+/// * `handler` - called with `data` each time
+/// # Returns
+/// The timer's ID for `timer_cancel`, or `None` if this hart has no
+/// free timers
+pub fn timer_periodic(ms: u64, handler: TimerHandler, data: usize) -> Option<usize> {
+    let period = ms_to_ticks(ms).max(1);
+    timer_add(timer_now() + period, period, handler, data)
+}
+
+/// # Overview
+/// Take a timer off of this hart's queue. A one-shot timer that already
+/// went off is gone, and its ID may belong to another timer by now, so
+/// only cancel those that haven't.
+/// # Returns
+/// `false` if there was no such timer
+pub fn timer_cancel(id: usize) -> bool {
+    let hart = csr_read!("mhartid");
+    without_interrupts(|| {
+        match unsafe { TIMERS[hart].get_mut(id) } {
+            Some(slot) if slot.is_some() => {
+                *slot = None;
+                timer_program(hart);
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+/// # Overview
+/// Handle the machine timer interrupt (cause 7). Every timer that is due
+/// is called, and periodic timers are put back in the queue. If a
+/// periodic timer fell more than a period behind, the ticks it missed
+/// are dropped instead of being called back to back.
+pub fn timer_handle() {
+    let hart = csr_read!("mhartid");
+    let now = timer_now();
+    for slot in 0..TIMER_SLOTS {
+        let timers = unsafe { &mut TIMERS[hart] };
+        let event = match timers[slot] {
+            Some(event) if event.deadline <= now => event,
+            _ => continue,
+        };
+        timers[slot] = match event.period {
+            0 => None,
+            period => {
+                let next = event.deadline + period;
+                let deadline = if next > now { next } else { now + period };
+                Some(TimerEvent { deadline, ..event })
+            }
+        };
+        // The handler may add or cancel timers, so the queue has to be
+        // up to date before it runs.
+        (event.handler)(event.data);
+    }
+    timer_program(hart);
+}
+
+fn sleep_wake(data: usize) {
+    unsafe {
+        write_volatile(data as *mut bool, true);
+    }
+}
+
+/// # Overview
+/// Wait some number of milliseconds with the hart in wfi. If interrupts
+/// are off, the timer still wakes the hart up, so this works in a trap
+/// too (it just spins on wfi).
+pub fn sleep_ms(ms: u64) {
+    let deadline = timer_deadline(ms);
+    let mut woken = false;
+    let id = timer_add(deadline, 0, sleep_wake, &mut woken as *mut bool as usize);
+    while !timer_expired(deadline) {
+        // Without a timer of our own, nothing may wake us up.
+        if id.is_some() {
+            unsafe {
+                asm!("wfi");
+            }
+        }
+    }
+    // If our timer hasn't gone off, it is still in its slot. It can't go
+    // off while we look, since interrupts are off.
+    without_interrupts(|| {
+        if let Some(id) = id {
+            if !unsafe { read_volatile(&woken) } {
+                timer_cancel(id);
+            }
+        }
+    });
+}
@@ -1,4 +1,4 @@
-use crate::imsic::{imsic_handle, PrivMode};
+use crate::{imsic::PrivMode, irq::controller, timer::timer_handle};
 
 #[no_mangle]
 pub fn rust_trap() {
@@ -8,8 +8,9 @@ pub fn rust_trap() {
     if interrupt {
         // Interrupt (asynchronous)
         match mcause & 0xFF {
-            9 => imsic_handle(PrivMode::Supervisor),
-            11 => imsic_handle(PrivMode::Machine),
+            7 => timer_handle(),
+            9 => controller().handle(PrivMode::Supervisor),
+            11 => controller().handle(PrivMode::Machine),
             _ => println!("Unknown interrupt #{}", mcause),
         }
     } else {
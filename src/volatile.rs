@@ -0,0 +1,40 @@
+//! volatile.rs
+//! Typed access to memory-mapped registers
+//! Stephen Marz
+//! 17-Oct-2026
+
+use core::{
+    cell::UnsafeCell,
+    ptr::{read_volatile, write_volatile},
+};
+
+/// A memory-mapped register. Every access is a single volatile read or
+/// write of the register's width, so the compiler can't elide, merge or
+/// reorder them. MMIO structures are made of these instead of plain
+/// fields and are only ever used through references to the device.
+#[repr(transparent)]
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> Volatile<T> {
+    pub fn read(&self) -> T {
+        unsafe { read_volatile(self.0.get()) }
+    }
+
+    pub fn write(&self, val: T) {
+        unsafe { write_volatile(self.0.get(), val) }
+    }
+
+    /// # Overview
+    /// Read the register, change the value and write it back.
+    /// # Arguments
+    /// * `f` - given the current value, returns the value to write
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+
+    /// The register's address, for handing to something that wants a
+    /// pointer.
+    pub fn as_ptr(&self) -> *mut T {
+        self.0.get()
+    }
+}